@@ -0,0 +1,368 @@
+use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::cfg::{Backtest, Cfg, Common};
+use crate::exchange::sim::{Fill, SimExchange};
+use crate::exchange::{Candle, SharedExchange};
+use crate::strats::clock::VirtualClock;
+use crate::strats::dca_dips::{ladder_from_cfg, market_buy_from_cfg, DipStrat};
+use crate::strats::dca_static::StaticStrat;
+use crate::strats::dca_twap::TwapStrat;
+use crate::strats::dca_value_avg::ValueAvgStrat;
+use crate::strats::order_util::split_client_order_id;
+use crate::strats::strat::{Execute, Strat};
+
+pub struct Report {
+    pub name: String,
+    pub symbol: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub fills: Vec<Fill>,
+}
+
+impl Report {
+    pub fn usd_spent(&self) -> Decimal {
+        self.fills
+            .iter()
+            .filter(|f| f.side == "buy")
+            .map(|f| f.price * f.amount)
+            .sum()
+    }
+
+    pub fn coins(&self) -> Decimal {
+        self.fills
+            .iter()
+            .filter(|f| f.side == "buy")
+            .map(|f| f.amount)
+            .sum()
+    }
+
+    pub fn log(&self) {
+        let usd = self.usd_spent();
+        let coins = self.coins();
+        info!(
+            "{{{}}}: {} backtest {} to {}",
+            self.symbol, self.name, self.start, self.end
+        );
+        info!(
+            "{{{}}}: {} spent ${:.2} for {:.8} avg cost ${:.2}",
+            self.symbol,
+            self.name,
+            usd.round_dp(2),
+            coins,
+            if coins > Decimal::ZERO {
+                (usd / coins).round_dp(2)
+            } else {
+                Decimal::ZERO
+            },
+        );
+
+        // fills are grouped by the tag in the client order id, which for the
+        // dips strategy is the bucket number
+        let tag = |f: &Fill| split_client_order_id(&f.client_order_id).1.to_owned();
+        let mut buckets: Vec<String> = vec![];
+        for fill in &self.fills {
+            if !buckets.contains(&tag(fill)) {
+                buckets.push(tag(fill));
+            }
+        }
+        buckets.sort_by_key(|b| b.parse::<u32>().unwrap_or(0));
+
+        for bucket in buckets {
+            let fills: Vec<&Fill> = self.fills.iter().filter(|f| tag(f) == bucket).collect();
+            let usd: Decimal = fills.iter().map(|f| f.price * f.amount).sum();
+            let coins: Decimal = fills.iter().map(|f| f.amount).sum();
+            let label = &bucket;
+            info!(
+                "{{{}}}: {} bucket {}: {} fills ${:.2} {:.8}",
+                self.symbol,
+                self.name,
+                label,
+                fills.len(),
+                usd.round_dp(2),
+                coins
+            );
+            for fill in fills {
+                info!(
+                    "{{{}}}: {} bucket {}:   {} {} id={} {:.8} @ ${:.2}",
+                    self.symbol,
+                    self.name,
+                    label,
+                    fill.time,
+                    fill.side,
+                    fill.order_id,
+                    fill.amount,
+                    fill.price
+                );
+            }
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    match s.parse::<i64>() {
+        Ok(ms) => Utc.timestamp_millis_opt(ms).single(),
+        Err(_) => DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|t| t.with_timezone(&Utc)),
+    }
+}
+
+/// Load candles from a csv file with rows of `time,open,high,low,close,volume`,
+/// where time is either unix milliseconds or RFC 3339. A header row is skipped.
+pub fn load_candles(path: &str) -> Option<Vec<Candle>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("could not read {}: {}", path, e);
+            return None;
+        }
+    };
+
+    let mut candles = vec![];
+    for (n, line) in data.lines().enumerate() {
+        let cols: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+        if cols.len() < 6 {
+            continue;
+        }
+        let time = parse_time(cols[0]);
+        let vals: Vec<Decimal> = cols[1..6].iter().filter_map(|c| c.parse().ok()).collect();
+        if time.is_none() || vals.len() != 5 {
+            if n > 0 {
+                warn!("{}:{}: skipping malformed candle", path, n + 1);
+            }
+            continue;
+        }
+        candles.push(Candle {
+            time: time.unwrap(),
+            high: vals[1],
+            low: vals[2],
+            close: vals[3],
+            volume: vals[4],
+        });
+    }
+    // gemini returns candles newest first
+    candles.sort_by_key(|c| c.time);
+
+    if candles.is_empty() {
+        error!("{}: no candles", path);
+        return None;
+    }
+    Some(candles)
+}
+
+/// Drive the strategy of a `[[strat_*]]` table through the candles on a
+/// simulated exchange.
+pub fn run<T: Execute>(
+    bt: &Backtest,
+    cfg: &Cfg,
+    common: &Common,
+    kind: &str,
+    candles: Vec<Candle>,
+    new_ctx: impl FnOnce(&Strat) -> Result<T, String>,
+) -> Result<Report, String> {
+    let mut sim = SimExchange::new(&common.symbol, candles).map_err(|e| e.to_string())?;
+    sim.fund("usd", bt.usd.unwrap_or_else(|| Decimal::from(1_000_000)));
+    sim.spread = bt.spread.unwrap_or_default();
+    sim.liquidity = bt.liquidity;
+    let start = sim.now();
+
+    let sim = Arc::new(Mutex::new(sim));
+    let client: SharedExchange = sim.clone();
+    let clock = Box::new(VirtualClock::new(Arc::clone(&sim)));
+    let mut strat = Strat::from_cfg(cfg, common, kind, client, clock)?;
+    // the simulator trades nothing real to begin with
+    strat.set_dry_run(false);
+    let mut ctx = new_ctx(&strat)?;
+    strat.run(&mut ctx, common.immediate);
+
+    let sim = sim.lock().unwrap();
+    Ok(Report {
+        name: strat.id().to_owned(),
+        symbol: common.symbol.clone(),
+        start,
+        end: sim.now(),
+        fills: sim.fills(),
+    })
+}
+
+fn candles_for(bt: &Backtest, symbol: &str) -> Option<Vec<Candle>> {
+    match bt.candles.get(symbol) {
+        Some(path) => load_candles(path),
+        None => {
+            warn!("{{{}}}: no candles configured, skipping", symbol);
+            None
+        }
+    }
+}
+
+// strats that trade on market data, the holdings of other strats or more
+// than one symbol, none of which the simulator has
+fn unsupported(common: &Common, kind: &str) {
+    warn!(
+        "{{{}}}: {} strats are not supported in backtest, skipping",
+        common.symbol, kind
+    );
+}
+
+fn log_report(common: &Common, report: Result<Report, String>) {
+    match report {
+        Ok(report) => report.log(),
+        Err(e) => error!("{{{}}}: could not backtest: {}", common.symbol, e),
+    }
+}
+
+/// Backtest every configured strategy and log a report for each.
+pub fn run_all(cfg: &Cfg) {
+    let bt = match &cfg.backtest {
+        Some(bt) => bt,
+        None => {
+            error!("backtest mode requires a [backtest] section in the config");
+            return;
+        }
+    };
+
+    for w_cfg in &cfg.strat_static {
+        if let Some(candles) = candles_for(bt, &w_cfg.common.symbol) {
+            let report = run(bt, cfg, &w_cfg.common, "static", candles, |strat| {
+                StaticStrat::from_cfg(strat.share(w_cfg.usd_per_trade), w_cfg)
+            });
+            log_report(&w_cfg.common, report);
+        }
+    }
+
+    for w_cfg in &cfg.strat_dips {
+        if let Some(candles) = candles_for(bt, &w_cfg.common.symbol) {
+            let report = run(bt, cfg, &w_cfg.common, "dips", candles, |strat| {
+                let rungs = ladder_from_cfg(w_cfg)?;
+                let market = market_buy_from_cfg(w_cfg)?;
+                Ok(DipStrat::new(
+                    strat.share(w_cfg.usd_per_window),
+                    rungs,
+                    market,
+                ))
+            });
+            log_report(&w_cfg.common, report);
+        }
+    }
+
+    for w_cfg in &cfg.strat_twap {
+        if let Some(candles) = candles_for(bt, &w_cfg.common.symbol) {
+            let report = run(bt, cfg, &w_cfg.common, "twap", candles, |strat| {
+                TwapStrat::from_cfg(strat.share(w_cfg.usd_per_window), w_cfg)
+            });
+            log_report(&w_cfg.common, report);
+        }
+    }
+
+    for w_cfg in &cfg.strat_value_avg {
+        if let Some(candles) = candles_for(bt, &w_cfg.common.symbol) {
+            let report = run(bt, cfg, &w_cfg.common, "value_avg", candles, |strat| {
+                ValueAvgStrat::from_cfg(w_cfg, |usd| strat.share(usd))
+            });
+            log_report(&w_cfg.common, report);
+        }
+    }
+
+    for w_cfg in &cfg.strat_drop {
+        unsupported(&w_cfg.common, "drop");
+    }
+    for w_cfg in &cfg.strat_take_profit {
+        unsupported(&w_cfg.common, "take_profit");
+    }
+    for w_cfg in &cfg.strat_rebalance {
+        unsupported(&w_cfg.common, "rebalance");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn csv(data: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+        file
+    }
+
+    fn load(data: &str) -> Option<Vec<Candle>> {
+        let file = csv(data);
+        load_candles(file.path().to_str().unwrap())
+    }
+
+    fn fill(side: &str, tag: &str, price: i64, amount: i64) -> Fill {
+        Fill {
+            time: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            order_id: 1,
+            client_order_id: format!("dips_btcusd:{}", tag),
+            side: side.to_owned(),
+            price: Decimal::from(price),
+            amount: Decimal::from(amount),
+        }
+    }
+
+    #[test]
+    fn loads_candles_oldest_first() {
+        let candles = load(
+            "time,open,high,low,close,volume\n\
+             2020-09-13T13:00:00Z,1,4,1,3,10\n\
+             1600000000000,1,2,1,2,20\n",
+        )
+        .unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(
+            candles[0].time,
+            Utc.timestamp_opt(1_600_000_000, 0).unwrap()
+        );
+        assert_eq!(candles[0].close, Decimal::from(2));
+        assert_eq!(
+            candles[1].time,
+            Utc.with_ymd_and_hms(2020, 9, 13, 13, 0, 0).unwrap()
+        );
+        assert_eq!(candles[1].high, Decimal::from(4));
+        assert_eq!(candles[1].low, Decimal::ONE);
+        assert_eq!(candles[1].volume, Decimal::from(10));
+    }
+
+    #[test]
+    fn skips_malformed_candles() {
+        let candles = load(
+            "1600000000000,1,2,1,2,20\n\
+             yesterday,1,2,1,2,20\n\
+             1600003600000,1,2,x,2,20\n\
+             1600007200000,1,2\n\
+             1600010800000,1,3,1,3,30\n",
+        )
+        .unwrap();
+        let closes: Vec<Decimal> = candles.iter().map(|c| c.close).collect();
+        assert_eq!(closes, vec![Decimal::from(2), Decimal::from(3)]);
+    }
+
+    #[test]
+    fn no_candles_is_none() {
+        assert!(load("time,open,high,low,close,volume\n").is_none());
+        assert!(load_candles("no/such/candles.csv").is_none());
+    }
+
+    #[test]
+    fn report_counts_only_buys() {
+        let report = Report {
+            name: "dips_btcusd".to_owned(),
+            symbol: "btcusd".to_owned(),
+            start: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            end: Utc.timestamp_opt(1_600_003_600, 0).unwrap(),
+            fills: vec![
+                fill("buy", "1", 100, 2),
+                fill("buy", "market", 50, 4),
+                fill("sell", "1", 200, 1),
+            ],
+        };
+        assert_eq!(report.usd_spent(), Decimal::from(400));
+        assert_eq!(report.coins(), Decimal::from(6));
+    }
+}
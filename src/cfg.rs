@@ -1,50 +1,249 @@
-use config::{Config, ConfigError, File};
+use config::{Config, File};
+use rust_decimal::Decimal;
 use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::error::Error;
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Api {
     pub url: String,
     pub key: String,
     pub sec: String,
+    pub ws_url: Option<String>,
+}
+
+impl Api {
+    /// The market data websocket, by default the url with its scheme
+    /// swapped for ws.
+    pub fn ws_url(&self) -> String {
+        match &self.ws_url {
+            Some(ws_url) => ws_url.clone(),
+            None => self.url.replacen("http", "ws", 1),
+        }
+    }
 }
 
+/// Configurations shared by every `[[strat_*]]` table.
 #[derive(Debug, Default, Deserialize)]
-pub struct StratStatic {
+pub struct Common {
+    pub id: Option<String>,
     pub symbol: String,
     pub sandbox: Option<bool>,
-    pub usd_per_trade: f32,
-    pub trade_day: u32,
-    pub trade_hr: u32,
-    pub trade_min: u32,
-    pub trade_sec: u32,
+    pub schedule: Option<String>,
+    pub timezone: Option<String>,
+    pub window_mins: Option<u32>,
+    pub slices: Option<u32>,
+    pub trade_day: Option<u32>,
+    pub trade_hr: Option<u32>,
+    pub trade_min: Option<u32>,
+    pub trade_sec: Option<u32>,
     pub immediate: Option<bool>,
+    pub reconcile: Option<String>,
+    pub shutdown: Option<String>,
+    pub catch_up: Option<String>,
+    pub insufficient_funds: Option<String>,
+    pub max_usd_per_order: Option<Decimal>,
+    pub max_usd_per_execution: Option<Decimal>,
+    pub max_usd_per_week: Option<Decimal>,
+    pub max_usd_per_month: Option<Decimal>,
+    pub max_usd_lifetime: Option<Decimal>,
+    pub max_spread_pct: Option<Decimal>,
+    pub max_deviation_pct: Option<Decimal>,
+    pub reference_frame: Option<String>,
+    pub reference_period: Option<u32>,
+    pub pause_mins: Option<u32>,
+    pub dry_run: Option<bool>,
+}
+
+impl Common {
+    /// The id the strat is saved under, `<kind>_<symbol>` unless set.
+    pub fn id(&self, kind: &str) -> String {
+        self.id
+            .clone()
+            .unwrap_or_else(|| format!("{}_{}", kind, self.symbol))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Multiplier {
+    pub indicator: String,
+    pub period: u32,
+    pub frame: Option<String>,
+    pub below: Option<Decimal>,
+    pub above: Option<Decimal>,
+    pub multiplier: Decimal,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StratStatic {
+    #[serde(flatten)]
+    pub common: Common,
+    pub usd_per_trade: Decimal,
+    #[serde(default)]
+    pub multipliers: Vec<Multiplier>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rung {
+    pub pct: Decimal,
+    pub weight: Decimal,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct StratDips {
+    #[serde(flatten)]
+    pub common: Common,
+    pub usd_per_window: Decimal,
+    pub dip_interval: Option<Decimal>,
+    pub dip_depth: Option<Decimal>,
+    pub weighting: Option<String>,
+    pub geometric_ratio: Option<Decimal>,
+    pub ladder: Option<Vec<Rung>>,
+    pub market_buy: Option<bool>,
+    pub market_buy_pct: Option<Decimal>,
+    pub market_retry_mins: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StratTwap {
+    #[serde(flatten)]
+    pub common: Common,
+    pub usd_per_window: Decimal,
+    pub child_orders: Option<u32>,
+    pub duration_mins: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StratValueAvg {
+    #[serde(flatten)]
+    pub common: Common,
+    pub value_per_window: Decimal,
+    pub min_usd_per_window: Option<Decimal>,
+    pub max_usd_per_window: Option<Decimal>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StratDrop {
+    #[serde(flatten)]
+    pub common: Common,
+    pub usd_per_window: Decimal,
+    pub usd_per_drop: Decimal,
+    pub drop_pct: Decimal,
+    pub drop_mins: u32,
+    pub cooldown_mins: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StratTakeProfit {
+    #[serde(flatten)]
+    pub common: Common,
+    // ids of the strats whose holdings are sold from
+    pub sources: Vec<String>,
+    pub portion_pct: Decimal,
+    pub profit_interval: Option<Decimal>,
+    pub profit_depth: Option<Decimal>,
+    pub weighting: Option<String>,
+    pub geometric_ratio: Option<Decimal>,
+    pub ladder: Option<Vec<Rung>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
     pub symbol: String,
-    pub sandbox: Option<bool>,
-    pub usd_per_window: u32,
-    pub trade_day: u32,
-    pub trade_hr: u32,
-    pub trade_min: u32,
-    pub trade_sec: u32,
-    pub immediate: Option<bool>,
+    pub weight: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StratRebalance {
+    // symbol is the quote currency the targets are valued in
+    #[serde(flatten)]
+    pub common: Common,
+    pub usd_per_window: Decimal,
+    pub targets: Vec<Target>,
+    pub sell_drift_pct: Option<Decimal>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Backtest {
+    // symbol => csv file of candles
+    pub candles: HashMap<String, String>,
+    pub usd: Option<Decimal>,
+    pub spread: Option<Decimal>,
+    pub liquidity: Option<Decimal>,
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Cfg {
     pub api: Api,
     pub sandbox: Api,
-    // TODO make these optional:
+    pub state_dir: Option<String>,
+    pub timezone: Option<String>,
+    pub reconcile: Option<String>,
+    pub shutdown: Option<String>,
+    pub catch_up: Option<String>,
+    pub insufficient_funds: Option<String>,
+    pub max_usd_per_order: Option<Decimal>,
+    pub max_usd_per_execution: Option<Decimal>,
+    pub max_usd_per_week: Option<Decimal>,
+    pub max_usd_per_month: Option<Decimal>,
+    pub max_usd_lifetime: Option<Decimal>,
+    pub max_spread_pct: Option<Decimal>,
+    pub max_deviation_pct: Option<Decimal>,
+    pub reference_frame: Option<String>,
+    pub reference_period: Option<u32>,
+    pub pause_mins: Option<u32>,
+    pub dry_run: Option<bool>,
+    #[serde(default)]
     pub strat_static: Vec<StratStatic>,
+    #[serde(default)]
     pub strat_dips: Vec<StratDips>,
+    #[serde(default)]
+    pub strat_twap: Vec<StratTwap>,
+    #[serde(default)]
+    pub strat_value_avg: Vec<StratValueAvg>,
+    #[serde(default)]
+    pub strat_drop: Vec<StratDrop>,
+    #[serde(default)]
+    pub strat_take_profit: Vec<StratTakeProfit>,
+    #[serde(default)]
+    pub strat_rebalance: Vec<StratRebalance>,
+    pub backtest: Option<Backtest>,
 }
 
 impl Cfg {
-    pub fn new() -> Result<Self, ConfigError> {
+    pub fn new() -> Result<Self, Error> {
         let mut c = Config::new();
-        c.merge(File::with_name("cfg")).unwrap();
-        c.try_into()
+        c.merge(File::with_name("cfg"))?;
+        Ok(c.try_into()?)
+    }
+
+    /// The id and symbol of every strat table.
+    pub fn strats(&self) -> Vec<(String, &str)> {
+        let commons = self
+            .strat_static
+            .iter()
+            .map(|s| (&s.common, "static"))
+            .chain(self.strat_dips.iter().map(|s| (&s.common, "dips")))
+            .chain(self.strat_twap.iter().map(|s| (&s.common, "twap")))
+            .chain(
+                self.strat_value_avg
+                    .iter()
+                    .map(|s| (&s.common, "value_avg")),
+            )
+            .chain(self.strat_drop.iter().map(|s| (&s.common, "drop")))
+            .chain(
+                self.strat_take_profit
+                    .iter()
+                    .map(|s| (&s.common, "take_profit")),
+            )
+            .chain(
+                self.strat_rebalance
+                    .iter()
+                    .map(|s| (&s.common, "rebalance")),
+            );
+        commons
+            .map(|(common, kind)| (common.id(kind), common.symbol.as_str()))
+            .collect()
     }
 }
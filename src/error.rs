@@ -0,0 +1,36 @@
+use config::ConfigError;
+use std::fmt;
+
+/// What went wrong, by how the strat runner can recover from it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // a request to the exchange that didn't complete, worth retrying
+    Transport(String),
+    // the exchange answered but refused what was asked, e.g. a taker
+    // order that would post
+    Rejected(String),
+    // an answer from the exchange, or a saved value, that doesn't make sense.
+    // asking again won't change it
+    Parse(String),
+    // a configuration that can't be run
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "request failed: {}", e),
+            Error::Rejected(e) => write!(f, "rejected by the exchange: {}", e),
+            Error::Parse(e) => write!(f, "could not parse {}", e),
+            Error::Config(e) => write!(f, "bad config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e.to_string())
+    }
+}
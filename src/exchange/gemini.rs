@@ -0,0 +1,298 @@
+use chrono::{TimeZone, Utc};
+use gemini_rust::{GeminiClient, PublicAPI};
+use hmac::{Hmac, Mac};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha384;
+use std::cell::Cell;
+use std::convert::{TryFrom, TryInto};
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::exchange::{Balance, Candle, Exchange, OrderStatus, SymbolDetail, Ticker};
+
+/// A Gemini account. The public endpoints go through gemini-rust, except
+/// for candles which it doesn't cover. The private ones are signed here, so
+/// orders are sent with their exact decimal amounts and prices and every
+/// request shares one nonce sequence.
+pub struct Gemini {
+    client: GeminiClient,
+    url: String,
+    key: String,
+    sec: String,
+    // the nonce of the last private request, each has to be higher
+    nonce: Cell<u64>,
+}
+
+impl Gemini {
+    pub fn new(url: &str, key: &str, sec: &str) -> Gemini {
+        Gemini {
+            client: GeminiClient::new(url, key, sec),
+            url: url.to_owned(),
+            key: key.to_owned(),
+            sec: sec.to_owned(),
+            nonce: Cell::new(0),
+        }
+    }
+
+    // the time in ms, or one more than the last nonce if the clock hasn't
+    // moved on since
+    fn next_nonce(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let nonce = now.max(self.nonce.get() + 1);
+        self.nonce.set(nonce);
+        nonce
+    }
+
+    // sign and send a request to a private endpoint, see
+    // https://docs.gemini.com/rest-api/#private-api-invocation
+    fn private<T: DeserializeOwned>(&self, request: &str, mut params: Value) -> Result<T, Error> {
+        params["request"] = json!(request);
+        params["nonce"] = json!(self.next_nonce().to_string());
+        let payload = base64::encode(params.to_string());
+        let mut mac =
+            Hmac::<Sha384>::new_from_slice(self.sec.as_bytes()).expect("hmac takes any key size");
+        mac.update(payload.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let response = ureq::post(&format!("{}{}", self.url, request))
+            .set("Content-Type", "text/plain")
+            .set("Cache-Control", "no-cache")
+            .set("X-GEMINI-APIKEY", &self.key)
+            .set("X-GEMINI-PAYLOAD", &payload)
+            .set("X-GEMINI-SIGNATURE", &signature)
+            .call();
+        let body = match response {
+            Ok(response) => response
+                .into_string()
+                .map_err(|e| Error::Transport(format!("{}: {}", request, e)))?,
+            // the exchange says why in the body of an error
+            Err(ureq::Error::Status(code, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                let reason = serde_json::from_str::<Value>(&body)
+                    .ok()
+                    .and_then(|v| v["reason"].as_str().map(str::to_owned))
+                    .unwrap_or(body);
+                let e = format!("{} {} {}", request, code, reason);
+                return Err(match code {
+                    // too many requests, worth asking again
+                    429 => Error::Transport(e),
+                    400..=499 => Error::Rejected(e),
+                    _ => Error::Transport(e),
+                });
+            }
+            Err(e) => return Err(Error::Transport(format!("{}: {}", request, e))),
+        };
+        serde_json::from_str(&body).map_err(|e| Error::Parse(format!("{} answer: {}", request, e)))
+    }
+}
+
+// the api has prices and amounts as strings, and a few as numbers
+fn dec<T: Display>(v: T, what: &str) -> Result<Decimal, Error> {
+    let s = v.to_string();
+    s.parse()
+        .or_else(|_| Decimal::from_scientific(&s))
+        .map_err(|_| Error::Parse(format!("{} {:?}", what, s)))
+}
+
+impl TryFrom<gemini_rust::Ticker> for Ticker {
+    type Error = Error;
+
+    fn try_from(tk: gemini_rust::Ticker) -> Result<Self, Error> {
+        Ok(Ticker {
+            bid: dec(tk.bid, "bid")?,
+            ask: dec(tk.ask, "ask")?,
+            last: dec(tk.last, "last")?,
+        })
+    }
+}
+
+impl TryFrom<gemini_rust::SymbolDetail> for SymbolDetail {
+    type Error = Error;
+
+    fn try_from(dt: gemini_rust::SymbolDetail) -> Result<Self, Error> {
+        Ok(SymbolDetail {
+            tick_size: dec(dt.tick_size, "tick size")?,
+            quote_increment: dec(dt.quote_increment, "quote increment")?,
+            min_order_size: dec(dt.min_order_size, "min order size")?,
+            base_currency: dt.base_currency,
+            quote_currency: dt.quote_currency,
+            status: dt.status,
+        })
+    }
+}
+
+// an order as the private endpoints answer with it
+#[derive(Debug, Deserialize)]
+struct RawOrder {
+    order_id: String,
+    client_order_id: Option<String>,
+    symbol: String,
+    side: String,
+    price: String,
+    avg_execution_price: String,
+    is_live: bool,
+    is_cancelled: bool,
+    executed_amount: String,
+    remaining_amount: String,
+    original_amount: String,
+    reason: Option<String>,
+}
+
+impl TryFrom<RawOrder> for OrderStatus {
+    type Error = Error;
+
+    fn try_from(os: RawOrder) -> Result<Self, Error> {
+        let what = |field: &str| format!("{} of order id={}", field, os.order_id);
+        Ok(OrderStatus {
+            price: dec(&os.price, &what("price"))?,
+            avg_execution_price: dec(&os.avg_execution_price, &what("avg execution price"))?,
+            executed_amount: dec(&os.executed_amount, &what("executed amount"))?,
+            remaining_amount: dec(&os.remaining_amount, &what("remaining amount"))?,
+            original_amount: dec(&os.original_amount, &what("original amount"))?,
+            is_live: os.is_live,
+            is_cancelled: os.is_cancelled,
+            order_id: os.order_id,
+            client_order_id: os.client_order_id,
+            symbol: os.symbol,
+            side: os.side,
+            reason: os.reason,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawBalance {
+    currency: String,
+    amount: String,
+    available: String,
+}
+
+impl TryFrom<RawBalance> for Balance {
+    type Error = Error;
+
+    fn try_from(b: RawBalance) -> Result<Self, Error> {
+        Ok(Balance {
+            amount: dec(&b.amount, &format!("{} balance", b.currency))?,
+            available: dec(&b.available, &format!("{} available", b.currency))?,
+            currency: b.currency,
+        })
+    }
+}
+
+// a number of the v2 api, which may come quoted
+fn num(v: &Value, what: &str) -> Result<Decimal, Error> {
+    match v {
+        Value::String(s) => dec(s, what),
+        v => dec(v, what),
+    }
+}
+
+// a candle of the v2 api, [time ms, open, high, low, close, volume]
+fn candle(row: &[Value]) -> Result<Candle, Error> {
+    let time = row
+        .first()
+        .and_then(Value::as_i64)
+        .and_then(|ms| Utc.timestamp_millis_opt(ms).single());
+    match time {
+        Some(time) if row.len() >= 6 => Ok(Candle {
+            time,
+            high: num(&row[2], "candle high")?,
+            low: num(&row[3], "candle low")?,
+            close: num(&row[4], "candle close")?,
+            volume: num(&row[5], "candle volume")?,
+        }),
+        _ => Err(Error::Parse(format!("candle {:?}", row))),
+    }
+}
+
+// gemini-rust answers None for any failure
+fn sent<T>(result: Option<T>, what: &str) -> Result<T, Error> {
+    result.ok_or_else(|| Error::Transport(what.to_owned()))
+}
+
+impl Exchange for Gemini {
+    fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error> {
+        let dt = PublicAPI::symbol_detail(&self.client, symbol);
+        sent(dt, &format!("{} details", symbol))?.try_into()
+    }
+
+    fn ticker(&self, symbol: &str) -> Result<Ticker, Error> {
+        let tk = PublicAPI::ticker(&self.client, symbol);
+        sent(tk, &format!("{} ticker", symbol))?.try_into()
+    }
+
+    fn candles(&self, symbol: &str, frame: &str) -> Result<Vec<Candle>, Error> {
+        let url = format!("{}/v2/candles/{}/{}", self.url, symbol, frame);
+        let body = ureq::get(&url)
+            .call()
+            .map_err(|e| Error::Transport(format!("{} {} candles: {}", symbol, frame, e)))?
+            .into_string()
+            .map_err(|e| Error::Transport(format!("{} {} candles: {}", symbol, frame, e)))?;
+        let rows: Vec<Vec<Value>> = serde_json::from_str(&body)
+            .map_err(|e| Error::Parse(format!("{} {} candles: {}", symbol, frame, e)))?;
+        let mut candles = rows
+            .iter()
+            .map(|row| candle(row))
+            .collect::<Result<Vec<_>, _>>()?;
+        // gemini returns candles newest first
+        candles.sort_by_key(|c| c.time);
+        Ok(candles)
+    }
+
+    fn new_order(
+        &self,
+        symbol: &str,
+        amount: Decimal,
+        price: Decimal,
+        side: &str,
+        type_: &str,
+        client_order_id: &str,
+        options: &[&str],
+    ) -> Result<OrderStatus, Error> {
+        // sent as the decimal strings the api takes, never rounded through
+        // a float
+        let params = json!({
+            "client_order_id": client_order_id,
+            "symbol": symbol,
+            "amount": amount.to_string(),
+            "price": price.to_string(),
+            "side": side,
+            "type": type_,
+            "options": options,
+        });
+        self.private::<RawOrder>("/v1/order/new", params)?
+            .try_into()
+    }
+
+    fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let params = json!({ "order_id": order_id });
+        self.private::<RawOrder>("/v1/order/status", params)?
+            .try_into()
+    }
+
+    fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let params = json!({ "order_id": order_id });
+        self.private::<RawOrder>("/v1/order/cancel", params)?
+            .try_into()
+    }
+
+    fn active_orders(&self) -> Result<Vec<OrderStatus>, Error> {
+        self.private::<Vec<RawOrder>>("/v1/orders", json!({}))?
+            .into_iter()
+            .map(OrderStatus::try_from)
+            .collect()
+    }
+
+    fn balances(&self) -> Result<Vec<Balance>, Error> {
+        self.private::<Vec<RawBalance>>("/v1/balances", json!({}))?
+            .into_iter()
+            .map(Balance::try_from)
+            .collect()
+    }
+}
@@ -0,0 +1,406 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::{debug, info, warn};
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration as Duration2;
+
+use tungstenite::http::Uri;
+use tungstenite::Message;
+
+/// A trade seen on the market data feed.
+#[derive(Debug, Clone)]
+pub struct Trade {
+    pub time: DateTime<Utc>,
+    pub price: Decimal,
+}
+
+/// The order book and recent trades of a symbol, kept up to date from
+/// Gemini's market data websocket.
+#[derive(Debug, Default)]
+pub struct Book {
+    // price => amount
+    pub bids: BTreeMap<Decimal, Decimal>,
+    pub asks: BTreeMap<Decimal, Decimal>,
+    // oldest first
+    pub trades: VecDeque<Trade>,
+}
+
+pub type SharedBook = Arc<Mutex<Book>>;
+
+/// Notified on every trade a feed sees, so strats sleeping on it can react.
+pub type Pulse = Arc<(Mutex<()>, Condvar)>;
+
+impl Book {
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.asks.keys().next().copied()
+    }
+
+    pub fn last_trade(&self) -> Option<&Trade> {
+        self.trades.back()
+    }
+
+    /// The highest price traded at or after `since`.
+    pub fn high_since(&self, since: DateTime<Utc>) -> Option<Decimal> {
+        self.trades
+            .iter()
+            .rev()
+            .take_while(|t| t.time >= since)
+            .map(|t| t.price)
+            .max()
+    }
+
+    // apply an "update" message, dropping trades older than `keep`.
+    // returns true if it had trades.
+    fn apply(&mut self, msg: &Value, keep: Duration) -> bool {
+        let time = msg
+            .get("timestampms")
+            .and_then(Value::as_i64)
+            .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+            .unwrap_or_else(Utc::now);
+        let events = match msg.get("events").and_then(Value::as_array) {
+            Some(events) => events,
+            None => return false,
+        };
+        let dec = |e: &Value, key: &str| {
+            e.get(key)
+                .and_then(Value::as_str)
+                .and_then(|s| s.parse::<Decimal>().ok())
+        };
+        let mut traded = false;
+        for e in events {
+            match e.get("type").and_then(Value::as_str) {
+                Some("change") => {
+                    let (price, remaining) = match (dec(e, "price"), dec(e, "remaining")) {
+                        (Some(price), Some(remaining)) => (price, remaining),
+                        _ => continue,
+                    };
+                    let side = match e.get("side").and_then(Value::as_str) {
+                        Some("bid") => &mut self.bids,
+                        Some("ask") => &mut self.asks,
+                        _ => continue,
+                    };
+                    if remaining.is_zero() {
+                        side.remove(&price);
+                    } else {
+                        side.insert(price, remaining);
+                    }
+                }
+                Some("trade") => {
+                    if let Some(price) = dec(e, "price") {
+                        self.trades.push_back(Trade { time, price });
+                        traded = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        while let Some(t) = self.trades.front() {
+            if t.time >= time - keep {
+                break;
+            }
+            self.trades.pop_front();
+        }
+        traded
+    }
+}
+
+// the die flag is set
+fn dying(cond: &(Mutex<bool>, Condvar)) -> bool {
+    *cond.0.lock().unwrap()
+}
+
+/// Keep a book of `symbol` from the market data websocket at `ws_url` on
+/// its own thread, with trades as far back as `keep`. The feed's pulse is
+/// notified on every trade, and the thread exits once the die flag is set.
+/// Dropped connections are retried.
+pub fn spawn_feed(
+    ws_url: &str,
+    symbol: &str,
+    keep: Duration,
+    cond: Arc<(Mutex<bool>, Condvar)>,
+) -> (SharedBook, Pulse, JoinHandle<()>) {
+    let url = format!("{}/v1/marketdata/{}?heartbeat=true", ws_url, symbol);
+    let symbol = symbol.to_owned();
+    let book: SharedBook = Arc::new(Mutex::new(Book::default()));
+    let pulse: Pulse = Default::default();
+    let shared = Arc::clone(&book);
+    let trades = Arc::clone(&pulse);
+    let handle = thread::spawn(move || {
+        let retry = Duration2::from_secs(15);
+        while !dying(&cond) {
+            if let Err(e) = follow_feed(&url, &symbol, keep, &shared, &trades, &cond) {
+                warn!(
+                    "{{{}}}: market data {} dropped: {}. retry in 15s",
+                    symbol, url, e
+                );
+                let (lock, cvar) = &*cond;
+                let die = lock.lock().unwrap();
+                let _ = cvar.wait_timeout(die, retry).unwrap();
+            }
+        }
+        info!("{{{}}}: market data terminated", symbol);
+    });
+    (book, pulse, handle)
+}
+
+// read the feed into the book until told to die or the connection fails
+fn follow_feed(
+    url: &str,
+    symbol: &str,
+    keep: Duration,
+    book: &SharedBook,
+    pulse: &(Mutex<()>, Condvar),
+    cond: &(Mutex<bool>, Condvar),
+) -> Result<(), String> {
+    let uri: Uri = url.parse().map_err(|e| format!("{}", e))?;
+    let host = uri.host().unwrap_or_default();
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") {
+            443
+        } else {
+            80
+        });
+    let stream = TcpStream::connect((host, port)).map_err(|e| e.to_string())?;
+    // a clone shares the socket, so the timeout can be set once the
+    // handshake is done. it lets the read loop check the die flag.
+    let socket = stream.try_clone().map_err(|e| e.to_string())?;
+    let (mut ws, _) = tungstenite::client_tls(url, stream).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(Duration2::from_secs(1)))
+        .map_err(|e| e.to_string())?;
+    info!("{{{}}}: market data connected to {}", symbol, url);
+
+    // the feed starts with the whole book
+    *book.lock().unwrap() = Book::default();
+    let mut sequence = None;
+    while !dying(cond) {
+        let text = match ws.read_message() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => return Err("closed by the server".to_owned()),
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        let msg: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        debug!("{{{}}}: market data {}", symbol, text);
+
+        // a missed message leaves the book wrong, start over
+        let seq = msg.get("socket_sequence").and_then(Value::as_u64);
+        if let (Some(last), Some(seq)) = (sequence, seq) {
+            if seq != last + 1 {
+                return Err(format!("sequence jumped from {} to {}", last, seq));
+            }
+        }
+        sequence = seq.or(sequence);
+
+        if msg.get("type").and_then(Value::as_str) == Some("update")
+            && book.lock().unwrap().apply(&msg, keep)
+        {
+            pulse.1.notify_all();
+        }
+    }
+    let _ = ws.close(None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::Api;
+    use crate::strats::clock::{die, Clock, RealClock};
+    use crate::strats::dca_drop::DropStrat;
+    use crate::strats::strat::Execute;
+    use serde_json::json;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Instant;
+
+    const T: i64 = 1_600_000_000_000;
+
+    // an update of `events` at `secs` past T
+    fn update(seq: u64, secs: i64, events: Value) -> Value {
+        json!({
+            "type": "update",
+            "socket_sequence": seq,
+            "timestampms": T + secs * 1000,
+            "events": events,
+        })
+    }
+
+    fn change(side: &str, price: &str, remaining: &str) -> Value {
+        json!({ "type": "change", "side": side, "price": price, "remaining": remaining })
+    }
+
+    fn trade(price: &str) -> Value {
+        json!({ "type": "trade", "price": price, "amount": "0.1" })
+    }
+
+    // a websocket server on localhost playing each session's messages to a
+    // connection in turn, then closing it. a session waits for a message on
+    // `go` before each of its messages past the first `ahead`.
+    fn serve(sessions: Vec<Vec<Value>>, ahead: usize, go: Option<Receiver<()>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = Api {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            ..Default::default()
+        };
+        thread::spawn(move || {
+            for msgs in sessions {
+                let (stream, _) = listener.accept().unwrap();
+                let mut ws = tungstenite::accept(stream).unwrap();
+                for (i, msg) in msgs.iter().enumerate() {
+                    if let (true, Some(go)) = (i >= ahead, &go) {
+                        go.recv().unwrap();
+                    }
+                    ws.write_message(Message::Text(msg.to_string())).unwrap();
+                }
+                let _ = ws.close(None);
+                while ws.read_message().is_ok() {}
+            }
+        });
+        api.ws_url()
+    }
+
+    fn no_die() -> (Mutex<bool>, Condvar) {
+        (Mutex::new(false), Condvar::new())
+    }
+
+    // wait for the book to see `n` trades
+    fn wait_for_trades(book: &SharedBook, n: usize) {
+        let start = Instant::now();
+        while book.lock().unwrap().trades.len() < n {
+            assert!(start.elapsed() < Duration2::from_secs(5), "no trades");
+            thread::sleep(Duration2::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn applies_changes_and_trades() {
+        let mut book = Book::default();
+        let keep = Duration::minutes(1);
+        let msg = update(
+            0,
+            0,
+            json!([
+                change("bid", "99", "1"),
+                change("ask", "101", "2"),
+                change("ask", "102", "3")
+            ]),
+        );
+        assert!(!book.apply(&msg, keep));
+        assert_eq!(book.best_bid(), Some(Decimal::from(99)));
+        assert_eq!(book.best_ask(), Some(Decimal::from(101)));
+
+        let msg = update(1, 30, json!([trade("101"), change("ask", "101", "0")]));
+        assert!(book.apply(&msg, keep));
+        assert_eq!(book.best_ask(), Some(Decimal::from(102)));
+        assert_eq!(book.last_trade().unwrap().price, Decimal::from(101));
+
+        // trades older than `keep` are dropped
+        let msg = update(2, 120, json!([trade("100")]));
+        assert!(book.apply(&msg, keep));
+        assert_eq!(book.trades.len(), 1);
+        assert_eq!(
+            book.high_since(Utc.timestamp_millis_opt(T).unwrap()),
+            Some(Decimal::from(100))
+        );
+    }
+
+    #[test]
+    fn sequence_gap_starts_the_book_over() {
+        let url = serve(
+            vec![
+                vec![
+                    update(
+                        0,
+                        0,
+                        json!([change("bid", "99", "1"), change("ask", "101", "2")]),
+                    ),
+                    update(1, 1, json!([trade("100"), change("bid", "99", "0")])),
+                    update(3, 2, json!([change("bid", "98", "1")])),
+                ],
+                vec![update(0, 3, json!([change("bid", "97", "1")]))],
+            ],
+            usize::MAX,
+            None,
+        );
+        let url = format!("{}/v1/marketdata/btcusd", url);
+        let book: SharedBook = Default::default();
+        let (cond, keep) = (no_die(), Duration::minutes(1));
+
+        let e = follow_feed(&url, "btcusd", keep, &book, &Default::default(), &cond).unwrap_err();
+        assert_eq!(e, "sequence jumped from 1 to 3");
+        {
+            let book = book.lock().unwrap();
+            assert!(book.bids.is_empty());
+            assert_eq!(book.best_ask(), Some(Decimal::from(101)));
+            assert_eq!(book.trades.len(), 1);
+        }
+
+        // the reconnect drops what the missed message left wrong
+        let e = follow_feed(&url, "btcusd", keep, &book, &Default::default(), &cond).unwrap_err();
+        assert_eq!(e, "closed by the server");
+        let book = book.lock().unwrap();
+        assert_eq!(book.best_bid(), Some(Decimal::from(97)));
+        assert!(book.asks.is_empty());
+        assert!(book.trades.is_empty());
+    }
+
+    #[test]
+    fn drop_strat_triggers_on_a_drop_in_the_feed() {
+        let (go, rx) = channel();
+        let url = serve(
+            vec![vec![
+                update(0, 0, json!([trade("100")])),
+                update(1, 60, json!([trade("97")])),
+                update(2, 120, json!([trade("94")])),
+            ]],
+            2,
+            Some(rx),
+        );
+        let cond = Arc::new(no_die());
+        let (book, pulse, handle) =
+            spawn_feed(&url, "btcusd", Duration::minutes(10), Arc::clone(&cond));
+        let clock = RealClock::with_feed(Arc::clone(&cond), Arc::clone(&pulse));
+
+        // $50 on a 5% drop within 10m, with the budget for one
+        let mut strat = DropStrat::new(
+            Decimal::from(50),
+            Decimal::from(50),
+            Decimal::from(5),
+            Duration::minutes(10),
+            Duration::minutes(10),
+            Arc::clone(&book),
+        );
+        strat.load_state(&json!({ "budget": "50" }));
+
+        wait_for_trades(&book, 2);
+        assert!(!strat.triggered());
+        // the trade wakes a strat sleeping on the feed
+        go.send(()).unwrap();
+        let start = Instant::now();
+        assert!(clock.sleep_until(Duration2::from_secs(60), &|| strat.triggered()));
+        assert!(start.elapsed() < Duration2::from_secs(30));
+
+        // and dying wakes it for good
+        let sleeper = thread::spawn(move || clock.sleep_until(Duration2::from_secs(60), &|| false));
+        thread::sleep(Duration2::from_millis(50));
+        die(&cond, &[pulse]);
+        assert!(!sleeper.join().unwrap());
+        handle.join().unwrap();
+    }
+}
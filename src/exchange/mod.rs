@@ -0,0 +1,113 @@
+pub mod gemini;
+pub mod market_data;
+pub mod sim;
+
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error;
+
+/// An exchange client shared between strategy threads.
+pub type SharedExchange = Arc<Mutex<dyn Exchange + Send>>;
+
+#[derive(Debug, Default, Clone)]
+pub struct Ticker {
+    pub bid: Decimal,
+    pub ask: Decimal,
+    pub last: Decimal,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SymbolDetail {
+    pub base_currency: String,
+    pub quote_currency: String,
+    pub tick_size: Decimal,
+    pub quote_increment: Decimal,
+    pub min_order_size: Decimal,
+    pub status: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrderStatus {
+    pub order_id: String,
+    pub client_order_id: Option<String>,
+    pub symbol: String,
+    pub side: String,
+    pub price: Decimal,
+    pub avg_execution_price: Decimal,
+    pub is_live: bool,
+    pub is_cancelled: bool,
+    pub executed_amount: Decimal,
+    pub remaining_amount: Decimal,
+    pub original_amount: Decimal,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Balance {
+    pub currency: String,
+    pub amount: Decimal,
+    pub available: Decimal,
+}
+
+/// One period of market data, its high, low and closing price.
+#[derive(Debug, Clone)]
+pub struct Candle {
+    pub time: DateTime<Utc>,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+}
+
+/// The length of one of the candle time frames Gemini serves.
+pub fn frame_duration(frame: &str) -> Option<Duration> {
+    match frame {
+        "1m" => Some(Duration::minutes(1)),
+        "5m" => Some(Duration::minutes(5)),
+        "15m" => Some(Duration::minutes(15)),
+        "30m" => Some(Duration::minutes(30)),
+        "1hr" => Some(Duration::hours(1)),
+        "6hr" => Some(Duration::hours(6)),
+        "1day" => Some(Duration::days(1)),
+        _ => None,
+    }
+}
+
+/// The surface of an exchange that the strategies are written against.
+///
+/// Every call fails with `Error::Transport` when the request could not be
+/// completed, `Error::Rejected` when the exchange refused it, and
+/// `Error::Parse` when its answer doesn't make sense.
+pub trait Exchange {
+    fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error>;
+
+    fn ticker(&self, symbol: &str) -> Result<Ticker, Error>;
+
+    /// Recent candles of the time frame, oldest first.
+    fn candles(&self, symbol: &str, frame: &str) -> Result<Vec<Candle>, Error>;
+
+    #[allow(clippy::too_many_arguments)]
+    fn new_order(
+        &self,
+        symbol: &str,
+        amount: Decimal,
+        price: Decimal,
+        side: &str,
+        type_: &str,
+        client_order_id: &str,
+        options: &[&str],
+    ) -> Result<OrderStatus, Error>;
+
+    fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error>;
+
+    fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error>;
+
+    /// All live orders on the account, across symbols.
+    fn active_orders(&self) -> Result<Vec<OrderStatus>, Error>;
+
+    fn balances(&self) -> Result<Vec<Balance>, Error>;
+}
@@ -0,0 +1,653 @@
+use chrono::{DateTime, TimeZone, Utc};
+use log::debug;
+use rust_decimal::Decimal;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use crate::error::Error;
+use crate::exchange::{
+    frame_duration, Balance, Candle, Exchange, OrderStatus, SymbolDetail, Ticker,
+};
+
+/// A single execution against a simulated order.
+#[derive(Debug, Clone)]
+pub struct Fill {
+    pub time: DateTime<Utc>,
+    pub order_id: u64,
+    pub client_order_id: String,
+    pub side: String,
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone)]
+struct SimOrder {
+    id: u64,
+    client_order_id: String,
+    symbol: String,
+    side: String,
+    price: Decimal,
+    original_amount: Decimal,
+    executed_amount: Decimal,
+    executed_value: Decimal,
+    is_live: bool,
+    is_cancelled: bool,
+    reason: Option<String>,
+}
+
+impl SimOrder {
+    fn remaining(&self) -> Decimal {
+        self.original_amount - self.executed_amount
+    }
+
+    fn status(&self) -> OrderStatus {
+        let avg_execution_price = if self.executed_amount > Decimal::ZERO {
+            self.executed_value / self.executed_amount
+        } else {
+            Decimal::ZERO
+        };
+        OrderStatus {
+            order_id: self.id.to_string(),
+            client_order_id: Some(self.client_order_id.clone()),
+            symbol: self.symbol.clone(),
+            side: self.side.clone(),
+            price: self.price,
+            avg_execution_price,
+            is_live: self.is_live,
+            is_cancelled: self.is_cancelled,
+            executed_amount: self.executed_amount,
+            remaining_amount: self.remaining(),
+            original_amount: self.original_amount,
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    cursor: usize,
+    orders: Vec<SimOrder>,
+    balances: HashMap<String, Decimal>,
+    fills: Vec<Fill>,
+    rejects: VecDeque<String>,
+}
+
+impl State {
+    fn fill(
+        &mut self,
+        idx: usize,
+        time: DateTime<Utc>,
+        price: Decimal,
+        amount: Decimal,
+        base: &str,
+        quote: &str,
+    ) {
+        let order = &mut self.orders[idx];
+        order.executed_amount += amount;
+        order.executed_value += amount * price;
+        if order.remaining() <= Decimal::ZERO {
+            order.is_live = false;
+        }
+        let (sign_base, sign_quote) = if order.side == "buy" {
+            (Decimal::ONE, Decimal::NEGATIVE_ONE)
+        } else {
+            (Decimal::NEGATIVE_ONE, Decimal::ONE)
+        };
+        let fill = Fill {
+            time,
+            order_id: order.id,
+            client_order_id: order.client_order_id.clone(),
+            side: order.side.clone(),
+            price,
+            amount,
+        };
+        debug!("{{{}}}: sim fill {:?}", order.symbol, fill);
+        *self
+            .balances
+            .entry(base.to_owned())
+            .or_insert(Decimal::ZERO) += sign_base * amount;
+        *self
+            .balances
+            .entry(quote.to_owned())
+            .or_insert(Decimal::ZERO) += sign_quote * amount * price;
+        self.fills.push(fill);
+    }
+
+    // funds held by live orders
+    fn reserved(&self, currency: &str, base: &str, quote: &str) -> Decimal {
+        self.orders
+            .iter()
+            .filter(|o| o.is_live)
+            .map(|o| match o.side.as_str() {
+                "buy" if currency == quote => o.remaining() * o.price,
+                "sell" if currency == base => o.remaining(),
+                _ => Decimal::ZERO,
+            })
+            .sum()
+    }
+}
+
+/// An in-process paper exchange for a single symbol.
+///
+/// Prices come from a scripted or replayed series of candles, and the
+/// exchange only moves forward when `advance` is called, so a given series
+/// and sequence of orders always produces the same fills.
+///
+/// Orders are matched like Gemini's "exchange limit" orders:
+/// - "immediate-or-cancel" fills against the ask (bid for sells) or is
+///   cancelled with "ImmediateOrCancelWouldPost".
+/// - "maker-or-cancel" rests on the book or is cancelled with
+///   "MakerOrCancelWouldTake".
+/// - resting orders fill at their limit price once a candle trades through it.
+///
+/// When `liquidity` is set, no order fills for more than that amount per
+/// candle, which produces partial fills.
+pub struct SimExchange {
+    symbol: String,
+    base: String,
+    quote: String,
+    candles: Vec<Candle>,
+    pub spread: Decimal,
+    pub liquidity: Option<Decimal>,
+    pub detail: SymbolDetail,
+    state: RefCell<State>,
+}
+
+impl SimExchange {
+    pub fn new(symbol: &str, candles: Vec<Candle>) -> Result<SimExchange, Error> {
+        if candles.is_empty() {
+            return Err(Error::Config(
+                "simulator needs at least one candle".to_owned(),
+            ));
+        }
+        // gemini symbols are <base><quote> with a three letter quote currency
+        let split = symbol.len().saturating_sub(3);
+        if split == 0 || !symbol.is_char_boundary(split) {
+            return Err(Error::Config(format!(
+                "{} is not a base and a three letter quote currency",
+                symbol
+            )));
+        }
+        let (base, quote) = symbol.split_at(split);
+        Ok(SimExchange {
+            symbol: symbol.to_owned(),
+            base: base.to_lowercase(),
+            quote: quote.to_lowercase(),
+            candles,
+            spread: Decimal::ZERO,
+            liquidity: None,
+            detail: SymbolDetail {
+                base_currency: base.to_uppercase(),
+                quote_currency: quote.to_uppercase(),
+                tick_size: Decimal::new(1, 8),
+                quote_increment: Decimal::new(1, 2),
+                min_order_size: Decimal::new(1, 5),
+                status: "open".to_owned(),
+            },
+            state: RefCell::new(State::default()),
+        })
+    }
+
+    /// Build flat candles from a list of prices spaced `interval` apart.
+    #[cfg(test)]
+    pub fn from_prices(
+        symbol: &str,
+        start: DateTime<Utc>,
+        interval: chrono::Duration,
+        prices: &[Decimal],
+    ) -> SimExchange {
+        let candles = prices
+            .iter()
+            .enumerate()
+            .map(|(i, p)| Candle {
+                time: start + interval * i as i32,
+                high: *p,
+                low: *p,
+                close: *p,
+                volume: Decimal::ZERO,
+            })
+            .collect();
+        SimExchange::new(symbol, candles).unwrap()
+    }
+
+    /// Add funds to the simulated account.
+    pub fn fund(&mut self, currency: &str, amount: Decimal) {
+        *self
+            .state
+            .get_mut()
+            .balances
+            .entry(currency.to_lowercase())
+            .or_insert(Decimal::ZERO) += amount;
+    }
+
+    /// Reject the next order placed with the given reason, regardless of price.
+    #[cfg(test)]
+    pub fn reject_next(&mut self, reason: &str) {
+        self.state.get_mut().rejects.push_back(reason.to_owned());
+    }
+
+    /// The time of the current candle.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.candle().time
+    }
+
+    pub fn candle(&self) -> &Candle {
+        &self.candles[self.state.borrow().cursor]
+    }
+
+    pub fn fills(&self) -> Vec<Fill> {
+        self.state.borrow().fills.clone()
+    }
+
+    /// Move to the next candle and fill any resting orders it trades through.
+    /// Returns false once the series is exhausted.
+    pub fn advance(&mut self) -> bool {
+        let state = self.state.get_mut();
+        if state.cursor + 1 >= self.candles.len() {
+            return false;
+        }
+        state.cursor += 1;
+        let candle = &self.candles[state.cursor];
+
+        for idx in 0..state.orders.len() {
+            let order = &state.orders[idx];
+            if !order.is_live {
+                continue;
+            }
+            let crossed = match order.side.as_str() {
+                "buy" => candle.low <= order.price,
+                _ => candle.high >= order.price,
+            };
+            if crossed {
+                let amount = match self.liquidity {
+                    Some(l) => order.remaining().min(l),
+                    None => order.remaining(),
+                };
+                let price = order.price;
+                state.fill(idx, candle.time, price, amount, &self.base, &self.quote);
+            }
+        }
+        true
+    }
+
+    // quotes sit on the quote increment like the real book's
+    fn ask(&self) -> Decimal {
+        let ask = self.candle().close * (Decimal::ONE + self.spread / Decimal::TWO);
+        (ask / self.detail.quote_increment).ceil() * self.detail.quote_increment
+    }
+
+    fn bid(&self) -> Decimal {
+        let bid = self.candle().close * (Decimal::ONE - self.spread / Decimal::TWO);
+        (bid / self.detail.quote_increment).floor() * self.detail.quote_increment
+    }
+
+    // the exchange answers requests for another symbol with an error
+    fn check_symbol(&self, symbol: &str) -> Result<(), Error> {
+        if symbol != self.symbol {
+            return Err(Error::Rejected(format!("InvalidSymbol {}", symbol)));
+        }
+        Ok(())
+    }
+
+    fn order_idx(&self, order_id: u64) -> Result<usize, Error> {
+        match (order_id as usize).checked_sub(1) {
+            Some(idx) if idx < self.state.borrow().orders.len() => Ok(idx),
+            _ => Err(Error::Rejected(format!("OrderNotFound {}", order_id))),
+        }
+    }
+}
+
+impl Exchange for SimExchange {
+    fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error> {
+        self.check_symbol(symbol)?;
+        Ok(self.detail.clone())
+    }
+
+    fn ticker(&self, symbol: &str) -> Result<Ticker, Error> {
+        self.check_symbol(symbol)?;
+        Ok(Ticker {
+            bid: self.bid(),
+            ask: self.ask(),
+            last: self.candle().close,
+        })
+    }
+
+    // the candles so far, merged into the time frame
+    fn candles(&self, symbol: &str, frame: &str) -> Result<Vec<Candle>, Error> {
+        self.check_symbol(symbol)?;
+        let secs = frame_duration(frame)
+            .ok_or_else(|| Error::Rejected(format!("InvalidTimeFrame {}", frame)))?
+            .num_seconds();
+        let mut merged: Vec<Candle> = vec![];
+        for c in &self.candles[..=self.state.borrow().cursor] {
+            let start = c.time.timestamp() / secs * secs;
+            match merged.last_mut() {
+                Some(m) if m.time.timestamp() == start => {
+                    m.high = m.high.max(c.high);
+                    m.low = m.low.min(c.low);
+                    m.close = c.close;
+                    m.volume += c.volume;
+                }
+                _ => merged.push(Candle {
+                    time: Utc.timestamp_opt(start, 0).unwrap(),
+                    ..c.clone()
+                }),
+            }
+        }
+        Ok(merged)
+    }
+
+    fn new_order(
+        &self,
+        symbol: &str,
+        amount: Decimal,
+        price: Decimal,
+        side: &str,
+        type_: &str,
+        client_order_id: &str,
+        options: &[&str],
+    ) -> Result<OrderStatus, Error> {
+        self.check_symbol(symbol)?;
+        if type_ != "exchange limit" {
+            return Err(Error::Rejected(format!("InvalidOrderType {}", type_)));
+        }
+        if amount <= Decimal::ZERO {
+            return Err(Error::Rejected(format!("InvalidQuantity {}", amount)));
+        }
+        let (ask, bid, time) = (self.ask(), self.bid(), self.now());
+        let mut state = self.state.borrow_mut();
+
+        // the exchange refuses orders that the account can't cover
+        let (currency, needed) = match side {
+            "buy" => (&self.quote, amount * price),
+            "sell" => (&self.base, amount),
+            _ => return Err(Error::Rejected(format!("InvalidSide {}", side))),
+        };
+        let available = state.balances.get(currency).copied().unwrap_or_default()
+            - state.reserved(currency, &self.base, &self.quote);
+        if needed > available {
+            debug!(
+                "{{{}}}: sim insufficient {}: need {} have {}",
+                symbol, currency, needed, available
+            );
+            return Err(Error::Rejected("InsufficientFunds".to_owned()));
+        }
+
+        let idx = state.orders.len();
+        state.orders.push(SimOrder {
+            id: idx as u64 + 1,
+            client_order_id: client_order_id.to_owned(),
+            symbol: symbol.to_owned(),
+            side: side.to_owned(),
+            price,
+            original_amount: amount,
+            executed_amount: Decimal::ZERO,
+            executed_value: Decimal::ZERO,
+            is_live: true,
+            is_cancelled: false,
+            reason: None,
+        });
+
+        let crosses = match side {
+            "buy" => price >= ask,
+            _ => price <= bid,
+        };
+        let reject = if let Some(reason) = state.rejects.pop_front() {
+            Some(reason)
+        } else if options.contains(&"immediate-or-cancel") && !crosses {
+            Some("ImmediateOrCancelWouldPost".to_owned())
+        } else if options.contains(&"maker-or-cancel") && crosses {
+            Some("MakerOrCancelWouldTake".to_owned())
+        } else {
+            None
+        };
+
+        if let Some(reason) = reject {
+            let order = &mut state.orders[idx];
+            order.is_live = false;
+            order.is_cancelled = true;
+            order.reason = Some(reason);
+        } else if crosses {
+            // take liquidity at the touch
+            let amount = match self.liquidity {
+                Some(l) => amount.min(l),
+                None => amount,
+            };
+            let touch = if side == "buy" { ask } else { bid };
+            state.fill(idx, time, touch, amount, &self.base, &self.quote);
+            if options.contains(&"immediate-or-cancel") && state.orders[idx].is_live {
+                let order = &mut state.orders[idx];
+                order.is_live = false;
+                order.is_cancelled = true;
+            }
+        }
+        Ok(state.orders[idx].status())
+    }
+
+    fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let idx = self.order_idx(order_id)?;
+        Ok(self.state.borrow().orders[idx].status())
+    }
+
+    fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let idx = self.order_idx(order_id)?;
+        let order = &mut self.state.borrow_mut().orders[idx];
+        if order.is_live {
+            order.is_live = false;
+            order.is_cancelled = true;
+            order.reason = Some("Requested".to_owned());
+        }
+        Ok(order.status())
+    }
+
+    fn active_orders(&self) -> Result<Vec<OrderStatus>, Error> {
+        let state = self.state.borrow();
+        Ok(state
+            .orders
+            .iter()
+            .filter(|o| o.is_live)
+            .map(SimOrder::status)
+            .collect())
+    }
+
+    fn balances(&self) -> Result<Vec<Balance>, Error> {
+        let state = self.state.borrow();
+        let mut balances: Vec<Balance> = state
+            .balances
+            .iter()
+            .map(|(currency, amount)| Balance {
+                currency: currency.to_uppercase(),
+                amount: *amount,
+                available: amount - state.reserved(currency, &self.base, &self.quote),
+            })
+            .collect();
+        balances.sort_by(|a, b| a.currency.cmp(&b.currency));
+        Ok(balances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn sim(prices: &[i64]) -> SimExchange {
+        let prices: Vec<Decimal> = prices.iter().map(|p| Decimal::from(*p)).collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, Duration::hours(1), &prices);
+        sim.fund("USD", Decimal::from(1000));
+        sim
+    }
+
+    fn balance(sim: &SimExchange, currency: &str) -> Decimal {
+        let balances = sim.balances().unwrap();
+        let b = balances.iter().find(|b| b.currency == currency).unwrap();
+        b.available
+    }
+
+    #[test]
+    fn needs_candles_and_a_quote_currency() {
+        let candles = sim(&[100]).candles;
+        assert!(matches!(
+            SimExchange::new("btcusd", vec![]),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            SimExchange::new("usd", candles.clone()),
+            Err(Error::Config(_))
+        ));
+        assert!(SimExchange::new("btcusd", candles).is_ok());
+    }
+
+    #[test]
+    fn taker_fills_at_the_ask_or_would_post() {
+        let sim = sim(&[100]);
+        let status = sim
+            .new_order(
+                "btcusd",
+                Decimal::ONE,
+                Decimal::from(100),
+                "buy",
+                "exchange limit",
+                "t:1",
+                &["immediate-or-cancel"],
+            )
+            .unwrap();
+        assert!(!status.is_live && !status.is_cancelled);
+        assert_eq!(status.executed_amount, Decimal::ONE);
+
+        let status = sim
+            .new_order(
+                "btcusd",
+                Decimal::ONE,
+                Decimal::from(99),
+                "buy",
+                "exchange limit",
+                "t:2",
+                &["immediate-or-cancel"],
+            )
+            .unwrap();
+        assert!(status.is_cancelled);
+        assert_eq!(status.reason.as_deref(), Some("ImmediateOrCancelWouldPost"));
+
+        let fills = sim.fills();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].order_id, 1);
+        // the funded and the traded currencies are one balance each
+        assert_eq!(sim.balances().unwrap().len(), 2);
+        assert_eq!(balance(&sim, "USD"), Decimal::from(900));
+        assert_eq!(balance(&sim, "BTC"), Decimal::ONE);
+    }
+
+    #[test]
+    fn resting_order_fills_partially_with_liquidity() {
+        let mut sim = sim(&[100, 90, 110, 90, 90]);
+        sim.liquidity = Some(Decimal::new(4, 1));
+        let status = sim
+            .new_order(
+                "btcusd",
+                Decimal::ONE,
+                Decimal::from(95),
+                "buy",
+                "exchange limit",
+                "t:1",
+                &["maker-or-cancel"],
+            )
+            .unwrap();
+        assert!(status.is_live);
+        // the usd of the resting order is held
+        assert_eq!(balance(&sim, "USD"), Decimal::from(905));
+
+        let mut executed = vec![];
+        while sim.advance() {
+            executed.push(sim.order_status(1).unwrap().executed_amount);
+        }
+        assert_eq!(
+            executed,
+            vec![
+                Decimal::new(4, 1),
+                Decimal::new(4, 1),
+                Decimal::new(8, 1),
+                Decimal::ONE
+            ]
+        );
+        assert!(!sim.order_status(1).unwrap().is_live);
+        assert!(sim
+            .fills()
+            .iter()
+            .all(|f| f.order_id == 1 && f.price == Decimal::from(95)));
+        assert_eq!(balance(&sim, "USD"), Decimal::from(905));
+        assert_eq!(balance(&sim, "BTC"), Decimal::ONE);
+    }
+
+    #[test]
+    fn taker_fills_partially_with_liquidity() {
+        let mut sim = sim(&[100]);
+        sim.liquidity = Some(Decimal::new(4, 1));
+        let status = sim
+            .new_order(
+                "btcusd",
+                Decimal::ONE,
+                Decimal::from(100),
+                "buy",
+                "exchange limit",
+                "t:1",
+                &["immediate-or-cancel"],
+            )
+            .unwrap();
+        assert!(status.is_cancelled);
+        assert_eq!(status.executed_amount, Decimal::new(4, 1));
+        assert_eq!(status.remaining_amount, Decimal::new(6, 1));
+    }
+
+    #[test]
+    fn reject_next_cancels_one_order() {
+        let mut sim = sim(&[100]);
+        sim.reject_next("ImmediateOrCancelWouldPost");
+        let order = |id| {
+            sim.new_order(
+                "btcusd",
+                Decimal::ONE,
+                Decimal::from(100),
+                "buy",
+                "exchange limit",
+                id,
+                &["immediate-or-cancel"],
+            )
+            .unwrap()
+        };
+        let status = order("t:1");
+        assert!(status.is_cancelled);
+        assert_eq!(status.executed_amount, Decimal::ZERO);
+        assert_eq!(status.reason.as_deref(), Some("ImmediateOrCancelWouldPost"));
+        assert_eq!(order("t:2").executed_amount, Decimal::ONE);
+    }
+
+    #[test]
+    fn refuses_orders_the_account_cant_cover() {
+        let sim = sim(&[100]);
+        let result = sim.new_order(
+            "btcusd",
+            Decimal::from(11),
+            Decimal::from(100),
+            "buy",
+            "exchange limit",
+            "t:1",
+            &[],
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            Error::Rejected("InsufficientFunds".to_owned())
+        );
+        let result = sim.new_order(
+            "ethusd",
+            Decimal::ONE,
+            Decimal::from(100),
+            "buy",
+            "exchange limit",
+            "t:1",
+            &[],
+        );
+        assert!(matches!(result, Err(Error::Rejected(_))));
+    }
+}
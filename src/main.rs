@@ -1,98 +1,260 @@
 use log::debug;
-use log::info;
-use log4rs;
+use log::{error, info};
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use gemini_rust::GeminiClient;
+mod backtest;
 
 mod cfg;
-use cfg::Cfg;
+use cfg::{Cfg, Common};
+
+mod error;
+use error::Error;
+
+mod exchange;
+use exchange::gemini::Gemini;
+use exchange::market_data::{spawn_feed, Pulse, SharedBook};
+use exchange::SharedExchange;
 
 mod strats;
+use strats::clock::{self, RealClock};
 use strats::dca_dips::*;
+use strats::dca_drop::*;
 use strats::dca_static::*;
-use strats::strat::Strat;
+use strats::dca_twap::*;
+use strats::dca_value_avg::*;
+use strats::funds::SharedReservations;
+use strats::rebalance::*;
+use strats::state::Store;
+use strats::strat::{Execute, Strat};
+use strats::take_profit::*;
+
+fn spawn<T: Execute + Send + 'static>(
+    mut strat: Strat,
+    mut ctx: T,
+    immediate: Option<bool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || strat.run(&mut ctx, immediate))
+}
+
+// a strat table that can't be run stops the bot before it trades
+fn or_exit<T>(result: Result<T, String>, symbol: &str) -> T {
+    match result.map_err(Error::Config) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{{{}}}: {}", symbol, e);
+            std::process::exit(1);
+        }
+    }
+}
 
 fn main() {
     log4rs::init_file("logcfg.yml", Default::default()).unwrap();
 
-    let cfg = Cfg::new().unwrap();
+    let cfg = match Cfg::new() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if std::env::args().nth(1).as_deref() == Some("backtest") {
+        backtest::run_all(&cfg);
+        return;
+    }
+
+    let cl = Gemini::new(&cfg.api.url, &cfg.api.key, &cfg.api.sec);
+    let sn = Gemini::new(&cfg.sandbox.url, &cfg.sandbox.key, &cfg.sandbox.sec);
 
-    let cl = GeminiClient::new(&cfg.api.url, &cfg.api.key, &cfg.api.sec);
-    let sn = GeminiClient::new(&cfg.sandbox.url, &cfg.sandbox.key, &cfg.sandbox.sec);
+    let client: SharedExchange = Arc::new(Mutex::new(cl));
+    let sandbox: SharedExchange = Arc::new(Mutex::new(sn));
 
-    let client = Arc::new(Mutex::new(cl));
-    let sandbox = Arc::new(Mutex::new(sn));
+    // usd reserved by the strats of each account
+    let reserved: SharedReservations = Default::default();
+    let sandbox_reserved: SharedReservations = Default::default();
 
     let cond = Arc::new((Mutex::new(false), Condvar::new()));
 
+    let state_dir = cfg.state_dir.clone().unwrap_or_else(|| "state".to_owned());
+    // dry runs keep their state apart so they never mix with real orders
+    let dry_run_dir = format!("{}/dry_run", state_dir);
+    let strat_dir = |strat: &Strat| match strat.dry_run() {
+        true => dry_run_dir.clone(),
+        false => state_dir.clone(),
+    };
+
+    // a strat for the table, trading on the sandbox if asked to
+    let new_strat = |common: &Common, kind: &str| {
+        let (client, reserved) = match common.sandbox.unwrap_or(false) {
+            true => (Arc::clone(&sandbox), Arc::clone(&sandbox_reserved)),
+            false => (Arc::clone(&client), Arc::clone(&reserved)),
+        };
+        let clock = Box::new(RealClock::new(Arc::clone(&cond)));
+        let mut strat = or_exit(
+            Strat::from_cfg(&cfg, common, kind, client, clock),
+            &common.symbol,
+        );
+        strat.set_store(Store::new(&strat_dir(&strat), strat.id()));
+        // what a dry run means to spend isn't really set aside
+        if !strat.dry_run() {
+            strat.set_reservations(reserved);
+        }
+        strat
+    };
+    let url = |common: &Common| match common.sandbox.unwrap_or(false) {
+        true => cfg.sandbox.url.clone(),
+        false => cfg.api.url.clone(),
+    };
+
     let mut handles = vec![];
 
-    for w_cfg in cfg.strat_static {
-        let client = Arc::clone(&client);
-        let sandbox = Arc::clone(&sandbox);
+    // one market data feed per symbol and environment, shared by the strats
+    // watching it
+    let mut feeds: HashMap<(String, bool), (SharedBook, Pulse)> = HashMap::new();
+    let mut feed_handles = vec![];
+
+    for w_cfg in &cfg.strat_static {
+        let strat = new_strat(&w_cfg.common, "static");
         info!(
-            "{{{}}}: static {} : buy ${:.2} every week",
-            w_cfg.symbol,
-            match w_cfg.sandbox.unwrap_or(false) {
-                true => &cfg.sandbox.url,
-                false => &cfg.api.url,
-            },
+            "{{{}}}: static {} : buy ${:.2} per run",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
             w_cfg.usd_per_trade,
         );
-        let cond_ = Arc::clone(&cond);
-        let handle = thread::spawn(move || {
-            let strat = Strat::new(
-                match w_cfg.sandbox.unwrap_or(false) {
-                    true => sandbox,
-                    false => client,
-                },
-                &w_cfg.symbol,
-                cond_,
-                w_cfg.trade_day as i64,
-                w_cfg.trade_hr,
-                w_cfg.trade_min,
-                w_cfg.trade_sec,
-            );
-            let mut ctx = StaticStrat::new(w_cfg.usd_per_trade);
-            strat.run(&mut ctx, w_cfg.immediate);
-        });
-        handles.push(handle);
+        let ctx = or_exit(
+            StaticStrat::from_cfg(strat.share(w_cfg.usd_per_trade), w_cfg),
+            &w_cfg.common.symbol,
+        );
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
+    }
+
+    for w_cfg in &cfg.strat_dips {
+        let strat = new_strat(&w_cfg.common, "dips");
+        info!(
+            "{{{}}}: dips {} : buy ${:.2} per window",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
+            w_cfg.usd_per_window,
+        );
+        let rungs = or_exit(ladder_from_cfg(w_cfg), &w_cfg.common.symbol);
+        let market = or_exit(market_buy_from_cfg(w_cfg), &w_cfg.common.symbol);
+        let ctx = DipStrat::new(strat.share(w_cfg.usd_per_window), rungs, market);
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
     }
 
-    for w_cfg in cfg.strat_dips {
-        let client = Arc::clone(&client);
-        let sandbox = Arc::clone(&sandbox);
+    for w_cfg in &cfg.strat_twap {
+        let strat = new_strat(&w_cfg.common, "twap");
         info!(
-            "{{{}}}: dips {} : buy ${:.2} every week",
-            w_cfg.symbol,
-            match w_cfg.sandbox.unwrap_or(false) {
-                true => &cfg.sandbox.url,
-                false => &cfg.api.url,
-            },
+            "{{{}}}: twap {} : buy ${:.2} per window",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
             w_cfg.usd_per_window,
         );
-        let cond_ = Arc::clone(&cond);
-        let handle = thread::spawn(move || {
-            let strat = Strat::new(
-                match w_cfg.sandbox.unwrap_or(false) {
-                    true => sandbox,
-                    false => client,
-                },
-                &w_cfg.symbol,
-                cond_,
-                w_cfg.trade_day as i64,
-                w_cfg.trade_hr,
-                w_cfg.trade_min,
-                w_cfg.trade_sec,
-            );
-            let mut ctx = DipStrat::new(w_cfg.usd_per_window, 1, 15);
-            strat.run(&mut ctx, w_cfg.immediate);
-        });
-        handles.push(handle);
+        let ctx = or_exit(
+            TwapStrat::from_cfg(strat.share(w_cfg.usd_per_window), w_cfg),
+            &w_cfg.common.symbol,
+        );
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
+    }
+
+    for w_cfg in &cfg.strat_value_avg {
+        let strat = new_strat(&w_cfg.common, "value_avg");
+        info!(
+            "{{{}}}: value_avg {} : grow by ${:.2} per window",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
+            w_cfg.value_per_window,
+        );
+        let ctx = or_exit(
+            ValueAvgStrat::from_cfg(w_cfg, |usd| strat.share(usd)),
+            &w_cfg.common.symbol,
+        );
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
+    }
+
+    for w_cfg in &cfg.strat_drop {
+        let mut strat = new_strat(&w_cfg.common, "drop");
+        info!(
+            "{{{}}}: drop {} : buy ${:.2} on {}% drops in {}m, ${:.2} per window",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
+            w_cfg.usd_per_drop,
+            w_cfg.drop_pct,
+            w_cfg.drop_mins,
+            w_cfg.usd_per_window,
+        );
+        let sandbox = w_cfg.common.sandbox.unwrap_or(false);
+        let book = feeds
+            .entry((w_cfg.common.symbol.clone(), sandbox))
+            .or_insert_with(|| {
+                let api = if sandbox { &cfg.sandbox } else { &cfg.api };
+                // trades are kept for the longest window watching them
+                let keep = cfg
+                    .strat_drop
+                    .iter()
+                    .filter(|d| d.common.symbol == w_cfg.common.symbol)
+                    .map(|d| d.drop_mins)
+                    .max()
+                    .unwrap_or(w_cfg.drop_mins);
+                let (book, pulse, handle) = spawn_feed(
+                    &api.ws_url(),
+                    &w_cfg.common.symbol,
+                    chrono::Duration::minutes(keep as i64),
+                    Arc::clone(&cond),
+                );
+                feed_handles.push(handle);
+                (book, pulse)
+            });
+        // woken by the feed's trades rather than the other strats' feeds
+        strat.set_clock(Box::new(RealClock::with_feed(
+            Arc::clone(&cond),
+            Arc::clone(&book.1),
+        )));
+        let ctx = or_exit(
+            DropStrat::from_cfg(
+                strat.share(w_cfg.usd_per_window),
+                w_cfg,
+                Arc::clone(&book.0),
+            ),
+            &w_cfg.common.symbol,
+        );
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
+    }
+
+    for w_cfg in &cfg.strat_take_profit {
+        let strat = new_strat(&w_cfg.common, "take_profit");
+        info!(
+            "{{{}}}: take_profit {} : sell {}% of {:?}",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
+            w_cfg.portion_pct,
+            w_cfg.sources,
+        );
+        let ctx = or_exit(
+            TakeProfitStrat::from_cfg(w_cfg, &cfg.strats(), &strat_dir(&strat)),
+            &w_cfg.common.symbol,
+        );
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
+    }
+
+    for w_cfg in &cfg.strat_rebalance {
+        let strat = new_strat(&w_cfg.common, "rebalance");
+        info!(
+            "{{{}}}: rebalance {} : buy ${:.2} per window across {} symbols",
+            w_cfg.common.symbol,
+            url(&w_cfg.common),
+            w_cfg.usd_per_window,
+            w_cfg.targets.len(),
+        );
+        let ctx = or_exit(
+            RebalanceStrat::from_cfg(strat.share(w_cfg.usd_per_window), w_cfg),
+            &w_cfg.common.symbol,
+        );
+        handles.push(spawn(strat, ctx, w_cfg.common.immediate));
     }
 
     // wait for CTL-C
@@ -104,7 +266,7 @@ fn main() {
         })
         .expect("Error setting Ctrl-C handler");
 
-        info!("Initiated. Ctrl-C to exit and terminate open orders.");
+        info!("Initiated. Ctrl-C to exit.");
 
         while running.load(Ordering::SeqCst) {
             thread::sleep(Duration::from_secs(5));
@@ -113,18 +275,15 @@ fn main() {
     }
 
     // signal threads to die
-    let (lock, cvar) = &*cond;
-    {
-        debug!("getting lock on die condvar");
-        let mut die = lock.lock().unwrap();
-        *die = true;
-    }
-    debug!("die is true, notify_all");
-    cvar.notify_all();
+    let pulses: Vec<Pulse> = feeds.values().map(|(_, pulse)| Arc::clone(pulse)).collect();
+    clock::die(&cond, &pulses);
     debug!("join threads");
 
-    // join threads
-    for handle in handles {
-        handle.join().unwrap();
+    // join threads. one that panicked mustn't stop the rest from shutting
+    // down cleanly
+    for handle in handles.into_iter().chain(feed_handles) {
+        if handle.join().is_err() {
+            error!("a worker thread panicked");
+        }
     }
 }
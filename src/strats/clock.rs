@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use log::debug;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::exchange::market_data::Pulse;
+use crate::exchange::sim::SimExchange;
+
+/// Source of time for a strategy loop.
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Sleep for the given interval. Returns false if the strategy
+    /// should terminate instead of waking up.
+    fn sleep(&self, dur: Duration) -> bool;
+
+    /// Sleep like `sleep`, waking early once `until` returns true. It is
+    /// checked whenever the clock is poked, e.g. by a market data feed.
+    fn sleep_until(&self, dur: Duration, _until: &dyn Fn() -> bool) -> bool {
+        self.sleep(dur)
+    }
+}
+
+/// Wall clock time, woken early by the die condvar, and by the trades of a
+/// market data feed when it has one.
+pub struct RealClock {
+    cond_: Arc<(Mutex<bool>, Condvar)>,
+    pulse: Option<Pulse>,
+}
+
+impl RealClock {
+    pub fn new(cond_: Arc<(Mutex<bool>, Condvar)>) -> RealClock {
+        RealClock { cond_, pulse: None }
+    }
+
+    pub fn with_feed(cond_: Arc<(Mutex<bool>, Condvar)>, pulse: Pulse) -> RealClock {
+        RealClock {
+            cond_,
+            pulse: Some(pulse),
+        }
+    }
+
+    fn dying(&self) -> bool {
+        *self.cond_.0.lock().unwrap()
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, dur: Duration) -> bool {
+        let (lock, cvar) = &*self.cond_;
+        let end = Instant::now() + dur;
+
+        let mut die = lock.lock().unwrap();
+        while !*die {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            die = cvar.wait_timeout(die, end - now).unwrap().0;
+        }
+        debug!("woke up");
+        debug!("die= {}", *die);
+        !*die
+    }
+
+    fn sleep_until(&self, dur: Duration, until: &dyn Fn() -> bool) -> bool {
+        let (lock, cvar) = match &self.pulse {
+            Some(pulse) => &**pulse,
+            None => return self.sleep(dur),
+        };
+        let end = Instant::now() + dur;
+
+        // the die flag is checked holding the pulse's lock, which `die`
+        // takes to notify it, so it can't be set unseen while waiting
+        let mut pulse = lock.lock().unwrap();
+        while !self.dying() {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            pulse = cvar.wait_timeout(pulse, end - now).unwrap().0;
+            if !self.dying() && until() {
+                break;
+            }
+        }
+        debug!("woke up");
+        !self.dying()
+    }
+}
+
+/// Set the die flag and wake every thread sleeping on it or on the `pulses`
+/// of the market data feeds.
+pub fn die(cond: &(Mutex<bool>, Condvar), pulses: &[Pulse]) {
+    let (lock, cvar) = cond;
+    {
+        debug!("getting lock on die condvar");
+        let mut die = lock.lock().unwrap();
+        *die = true;
+    }
+    debug!("die is true, notify_all");
+    cvar.notify_all();
+    for pulse in pulses {
+        let _lock = pulse.0.lock().unwrap();
+        pulse.1.notify_all();
+    }
+}
+
+/// Simulated time that advances a SimExchange through its candles
+/// instead of sleeping. Terminates once the candles run out.
+pub struct VirtualClock {
+    exchange: Arc<Mutex<SimExchange>>,
+}
+
+impl VirtualClock {
+    pub fn new(exchange: Arc<Mutex<SimExchange>>) -> VirtualClock {
+        VirtualClock { exchange }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.exchange.lock().unwrap().now()
+    }
+
+    fn sleep(&self, dur: Duration) -> bool {
+        let mut exchange = self.exchange.lock().unwrap();
+        let target = exchange.now() + chrono::Duration::from_std(dur).unwrap();
+        while exchange.now() < target {
+            if !exchange.advance() {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -1,97 +1,379 @@
 use log::{debug, error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::str::FromStr;
 use std::thread;
 use std::time::Duration;
 
-use gemini_rust::{GeminiClient, OrderPlacerAPI, OrderStatusAPI, SymbolDetail, Ticker};
+use crate::cfg::{self, StratDips};
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
 
 use crate::strats::order_util;
+use crate::strats::state::{strat_state, Position};
 use crate::strats::strat;
 use crate::strats::strat_util;
 use order_util::*;
 use strat::Execute;
 use strat_util::*;
 
+/// One order of the ladder, `pct` away from the price it is laid from.
+#[derive(Debug, Clone)]
+pub struct Rung {
+    pub pct: Decimal,
+    pub weight: Decimal,
+}
+
+/// How the usd of the window is spread across the rungs of the ladder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Weighting {
+    Flat,
+    // the nth rung gets n times the weight of the first
+    Linear,
+    // each rung gets `ratio` times the weight of the one above it
+    Geometric(Decimal),
+}
+
+impl FromStr for Weighting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Weighting::Flat),
+            "linear" => Ok(Weighting::Linear),
+            "geometric" => Ok(Weighting::Geometric(Decimal::new(125, 2))),
+            _ => Err(format!("unknown weighting: {}", s)),
+        }
+    }
+}
+
+/// The weighting named in a strat table, with its geometric ratio if set.
+pub fn weighting_from_cfg(
+    weighting: Option<&str>,
+    ratio: Option<Decimal>,
+) -> Result<Weighting, String> {
+    let weighting: Weighting = weighting.unwrap_or("flat").parse()?;
+    match (weighting, ratio) {
+        (Weighting::Geometric(_), Some(ratio)) => Ok(Weighting::Geometric(ratio)),
+        (weighting, _) => Ok(weighting),
+    }
+}
+
+/// Rungs every `interval` percent down to `depth` percent.
+pub fn ladder(
+    interval: Decimal,
+    depth: Decimal,
+    weighting: &Weighting,
+) -> Result<Vec<Rung>, String> {
+    if interval <= Decimal::ZERO {
+        return Err(format!("ladder interval {} needs to be > 0", interval));
+    }
+    if depth < interval {
+        return Err(format!(
+            "ladder depth {} needs to be >= its interval {}",
+            depth, interval
+        ));
+    }
+    let mut rungs = vec![];
+    let mut pct = interval;
+    let mut weight = Decimal::ONE;
+    while pct <= depth {
+        rungs.push(Rung { pct, weight });
+        weight = match weighting {
+            Weighting::Flat => Decimal::ONE,
+            Weighting::Linear => weight + Decimal::ONE,
+            Weighting::Geometric(ratio) => weight * ratio,
+        };
+        pct += interval;
+    }
+    Ok(rungs)
+}
+
+/// The rungs of an explicit `ladder` list, nearest the price first.
+pub fn explicit_ladder(rungs: &[cfg::Rung]) -> Result<Vec<Rung>, String> {
+    let mut rungs: Vec<Rung> = rungs
+        .iter()
+        .map(|r| Rung {
+            pct: r.pct,
+            weight: r.weight,
+        })
+        .collect();
+    rungs.sort_by_key(|r| r.pct);
+    if let Some(w) = rungs.windows(2).find(|w| w[0].pct == w[1].pct) {
+        return Err(format!("ladder has more than one rung at {}%", w[0].pct));
+    }
+    Ok(rungs)
+}
+
+/// The ladder described by a `[[strat_dips]]` table. An explicit `ladder`
+/// list takes precedence over the interval, depth and weighting.
+pub fn ladder_from_cfg(cfg: &StratDips) -> Result<Vec<Rung>, String> {
+    let rungs = match &cfg.ladder {
+        Some(rungs) => explicit_ladder(rungs)?,
+        None => {
+            let weighting = weighting_from_cfg(cfg.weighting.as_deref(), cfg.geometric_ratio)?;
+            ladder(
+                cfg.dip_interval.unwrap_or(Decimal::ONE),
+                cfg.dip_depth.unwrap_or_else(|| Decimal::from(15)),
+                &weighting,
+            )?
+        }
+    };
+    if rungs.is_empty() {
+        return Err("dips ladder has no rungs".to_owned());
+    }
+    if rungs.iter().any(|r| {
+        r.pct <= Decimal::ZERO || r.pct >= Decimal::ONE_HUNDRED || r.weight < Decimal::ZERO
+    }) {
+        return Err("dips ladder rungs need 0 < pct < 100 and weight >= 0".to_owned());
+    }
+    if rungs.iter().map(|r| r.weight).sum::<Decimal>().is_zero() {
+        return Err("dips ladder weights sum to zero".to_owned());
+    }
+    Ok(rungs)
+}
+
+/// The market order placed at the ask before laying the ladder.
+#[derive(Debug, Clone)]
+pub struct MarketBuy {
+    pub enabled: bool,
+    // percent of the window it takes. when unset it is sized like a
+    // flat rung and the ladder still gets the whole window.
+    pub pct: Option<Decimal>,
+    // retry a cancelled order after this long instead of adding it to
+    // the first bucket
+    pub retry: Option<Duration>,
+}
+
+/// The market order described by a `[[strat_dips]]` table.
+pub fn market_buy_from_cfg(cfg: &StratDips) -> Result<MarketBuy, String> {
+    if let Some(pct) = cfg.market_buy_pct {
+        if pct < Decimal::ZERO || pct > Decimal::ONE_HUNDRED {
+            return Err("market_buy_pct needs 0 <= pct <= 100".to_owned());
+        }
+    }
+    Ok(MarketBuy {
+        enabled: cfg.market_buy.unwrap_or(true),
+        pct: cfg.market_buy_pct,
+        retry: cfg
+            .market_retry_mins
+            .map(|mins| Duration::from_secs(mins as u64 * 60)),
+    })
+}
+
 pub struct DipStrat {
-    pub usd_per_window: u32,
-    dip_interval: u32,
-    n_trades: u32,
-    buckets: Vec<f32>,
-    usd_per_trade: f32,
+    rungs: Vec<Rung>,
+    n_trades: usize,
+    // each rung's share of the window
+    rung_usd: Vec<Decimal>,
+    market: MarketBuy,
+    market_usd: Decimal,
+    // usd of a cancelled market order waiting to be retried
+    pending_market: Decimal,
+    // usd below the minimum order size after the deepest bucket, or of
+    // refused orders, carried into the next cycle
+    deferred: Decimal,
+    // windows missed while down, added to the buckets on the next cycle
+    missed: u32,
+    // cap on the usd of the next execution when funds are short
+    limit: Option<Decimal>,
+    position: Position,
 }
 
 impl DipStrat {
-    pub fn new(usd_per_window: u32, dip_interval: u32, dip_bot: u32) -> DipStrat {
-        let mut d = DipStrat {
-            usd_per_window,
-            dip_interval,
-            n_trades: dip_bot / dip_interval,
-            buckets: vec![],
-            usd_per_trade: usd_per_window as f32 / (dip_bot / dip_interval) as f32,
+    pub fn new(usd_per_window: Decimal, rungs: Vec<Rung>, market: MarketBuy) -> DipStrat {
+        let n_trades = rungs.len();
+        let (market_usd, ladder_usd) = match (market.enabled, market.pct) {
+            (false, _) => (Decimal::ZERO, usd_per_window),
+            (true, None) => (usd_per_window / Decimal::from(n_trades), usd_per_window),
+            (true, Some(pct)) => {
+                let market_usd = usd_per_window * pct / Decimal::ONE_HUNDRED;
+                (market_usd, usd_per_window - market_usd)
+            }
         };
-        for _ in 1..=d.n_trades {
-            d.buckets.push(0.0);
+        let total_weight: Decimal = rungs.iter().map(|r| r.weight).sum();
+        let rung_usd = rungs
+            .iter()
+            .map(|r| ladder_usd * r.weight / total_weight)
+            .collect();
+        DipStrat {
+            rungs,
+            n_trades,
+            rung_usd,
+            market,
+            market_usd,
+            pending_market: Decimal::ZERO,
+            deferred: Decimal::ZERO,
+            missed: 0,
+            limit: None,
+            position: Position::default(),
         }
-        d
     }
+
+    // place a market order to be fulfilled now. returns the usd left
+    // unfilled when it was cancelled or refused.
+    fn market_order(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        dt: &SymbolDetail,
+        ask: Decimal,
+        usd: Decimal,
+    ) -> Decimal {
+        let amount = get_amount(ask, usd);
+        let client_order_id = client_order_id(id, "market");
+        let status = new_taker_order(client, dt, &client_order_id, symbol, Side::Buy, amount, ask);
+        thread::sleep(Duration::from_millis(10));
+        match placed_taker(symbol, &client_order_id, status) {
+            Placed::Order(status) => {
+                self.position.add_fill(&status);
+                match status.is_cancelled {
+                    // the usd of what didn't fill before it was cancelled
+                    true if !status.original_amount.is_zero() => {
+                        usd * status.remaining_amount / status.original_amount
+                    }
+                    _ => Decimal::ZERO,
+                }
+            }
+            Placed::Refused => usd,
+            Placed::Unknown => Decimal::ZERO,
+        }
+    }
+}
+
+// what is carried into the next cycle and what was bought. the buckets
+// are rebuilt from the rungs every cycle, so they aren't kept
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct DipState {
+    deferred: Decimal,
+    pending_market: Decimal,
+    position: Position,
 }
 
 impl Execute for DipStrat {
     fn execute(
         &mut self,
-        client: &GeminiClient,
+        client: &dyn Exchange,
+        id: &str,
         symbol: &str,
         orders: &mut Vec<u64>,
-        _dt: &SymbolDetail,
+        dt: &SymbolDetail,
         tk: &Ticker,
-    ) -> bool {
-        // add to the buckets
-        for bucket in &mut self.buckets {
-            *bucket = self.usd_per_trade;
+    ) -> Result<(), Error> {
+        // check on status of limit orders
+        let statuses = order_statuses(client, symbol, orders)?;
+
+        // the share of the new usd there are funds for
+        let needed = self.usd_needed(tk, false);
+        let scale = match self.limit.take() {
+            Some(limit) if limit < needed => limit / needed,
+            _ => Decimal::ONE,
+        };
+        if scale < Decimal::ONE {
+            info!(
+                "{{{}}}: scaling the window down to {:.1}% for lack of funds",
+                symbol,
+                scale * Decimal::ONE_HUNDRED
+            );
         }
-        debug!("woke up, buckets: {:#?}", self.buckets);
 
-        let ask: f32 = tk.ask.parse().unwrap();
+        // the usd for each rung's order, its share of the window plus what
+        // is carried into it
+        let mut buckets: Vec<Decimal> = self.rung_usd.iter().map(|usd| *usd * scale).collect();
+        // what is short of funds stays deferred
+        let deferred = self.deferred * scale;
+        if let Some(last) = buckets.last_mut() {
+            *last += deferred;
+        }
+        self.deferred -= deferred;
+        if self.missed > 0 {
+            // as if each missed window's orders went unfilled
+            let missed = Decimal::from(self.missed);
+            for (bucket, usd) in buckets.iter_mut().zip(&self.rung_usd) {
+                *bucket += *usd * missed * scale;
+            }
+            buckets[0] += self.market_usd * missed * scale;
+            info!(
+                "{{{}}}: added {} missed windows to the buckets",
+                symbol, self.missed
+            );
+            self.missed = 0;
+        }
+        debug!("woke up, buckets: {:#?}", buckets);
 
-        // check on status of limit orders
-        for order_id in orders.iter_mut() {
-            let status = client.order_status(Some(*order_id), None).unwrap();
-            let client_order_id = status.client_order_id.unwrap();
-            let mut bucket_num: usize = client_order_id.parse().unwrap();
-            bucket_num -= 1;
+        let ask = tk.ask;
+
+        // orders whose cancel didn't go through, checked on again next window
+        let mut unsettled = vec![];
+        for (order_id, status) in statuses {
+            let client_order_id = status.client_order_id.clone().unwrap_or_default();
+            let (_, client_order_id) = split_client_order_id(&client_order_id);
+            let bucket_num = match client_order_id.parse::<usize>() {
+                // the ladder may have been reconfigured since the order was placed
+                Ok(n) if n > 0 => (n - 1).min(buckets.len() - 1),
+                _ => {
+                    warn!(
+                        "{{{}}}: unknown order {} id={}",
+                        symbol, client_order_id, order_id
+                    );
+                    continue;
+                }
+            };
+            let pct = self.rungs[bucket_num].pct;
 
             // cancel live orders and add remainder to that bucket's next trade
             if status.is_live {
-                let remaining_amt: f32 = status.remaining_amount.parse().unwrap();
-                let remaining_usd = get_usd(remaining_amt, status.price.parse().unwrap());
-                self.buckets[bucket_num] += remaining_usd;
+                let status = match client.cancel_order(order_id) {
+                    Ok(status) if status.is_cancelled => status,
+                    Ok(status) => {
+                        error!(
+                            "{{{}}}: error cancelling {} order id={} ({}), checking on it next window",
+                            symbol,
+                            client_order_id,
+                            order_id,
+                            status.reason.unwrap_or_default()
+                        );
+                        unsettled.push(order_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(
+                            "{{{}}}: could not cancel {} order id={}: {}, checking on it next window",
+                            symbol, client_order_id, order_id, e
+                        );
+                        unsettled.push(order_id);
+                        continue;
+                    }
+                };
+                let remaining_usd = get_usd(status.remaining_amount, status.price);
+                buckets[bucket_num] += remaining_usd;
                 info!(
                         "{{{}}}: {} order id={} has ${:.2} remaining on it. cancelling and adding to bucket {}% = ${:.2}",
                         symbol, client_order_id, status.order_id,
-                        remaining_usd, client_order_id, self.buckets[bucket_num]
-                    );
-                let status = client.cancel_order(*order_id).unwrap();
-                if !status.is_cancelled {
-                    error!(
-                        "{{{}}}: error cancelling order! ({})",
-                        symbol,
-                        status.reason.unwrap_or("".to_string())
+                        remaining_usd, pct, buckets[bucket_num]
                     );
-                }
+                // what filled before the cancel went through
+                self.position.add_fill(&status);
 
             // add remainder of cancelled orders to that bucket's next trade
             } else if status.is_cancelled {
-                let remaining_amt: f32 = status.remaining_amount.parse().unwrap();
-                let remaining_usd = get_usd(remaining_amt, status.price.parse().unwrap());
-                if remaining_usd > 0.0 {
-                    self.buckets[bucket_num] += remaining_usd;
+                self.position.add_fill(&status);
+                let remaining_usd = get_usd(status.remaining_amount, status.price);
+                if remaining_usd > Decimal::ZERO {
+                    buckets[bucket_num] += remaining_usd;
                     info!(
                             "{{{}}}: {} order id={} cancelled, had ${:.2} remaining on it, adding to bucket {}% = ${:.2}",
                             symbol, client_order_id,  status.order_id,
-                            remaining_usd, client_order_id, self.buckets[bucket_num]
+                            remaining_usd, pct, buckets[bucket_num]
                         );
                 }
-            // reset that bucket
+            // the bucket keeps its share of this window and what was
+            // carried into it
             } else {
+                self.position.add_fill(&status);
                 info!(
                     "{{{}}}: {} order id={} fulfilled! {} @ ${}",
                     symbol,
@@ -100,58 +382,340 @@ impl Execute for DipStrat {
                     status.executed_amount,
                     status.avg_execution_price
                 );
-                self.buckets[bucket_num] = self.usd_per_trade;
             }
         }
-        orders.clear();
+        *orders = unsettled;
 
-        // place a market order to be fulfilled now
-        {
-            let price = ask;
-            let amount = get_amount(price, self.usd_per_trade);
+        // a market order still waiting on a retry from last cycle, already
+        // scaled when it was placed
+        if self.pending_market > Decimal::ZERO {
             info!(
-                "{{{}}}: n_trades={} usd_per_trade {:.2}",
-                symbol, self.n_trades, self.usd_per_trade
+                "{{{}}}: adding ${:.2} of unfilled market order to bucket {}%",
+                symbol, self.pending_market, self.rungs[0].pct
             );
-            let status = new_taker_order(&client, "", symbol, amount, price);
-            if status.is_cancelled {
-                // TODO retry in some minutes ? for now just add the amount back in
-                self.buckets[0] += self.usd_per_trade;
+            buckets[0] += self.pending_market;
+            self.pending_market = Decimal::ZERO;
+        }
+
+        if self.market.enabled {
+            info!(
+                "{{{}}}: n_trades={} market order {:.2}",
+                symbol, self.n_trades, self.market_usd
+            );
+            let usd = self.market_usd * scale;
+            let unfilled = self.market_order(client, id, symbol, dt, ask, usd);
+            if unfilled > Decimal::ZERO {
+                match self.market.retry {
+                    Some(retry) if !below_min_order_size(dt, get_amount(ask, unfilled)) => {
+                        info!(
+                            "{{{}}}: market order cancelled, retrying ${:.2} in {}m",
+                            symbol,
+                            unfilled,
+                            retry.as_secs() / 60
+                        );
+                        self.pending_market = unfilled;
+                    }
+                    _ => buckets[0] += unfilled,
+                }
             }
-            thread::sleep(Duration::from_millis(10))
         }
 
-        info!("buckets: {:#?}", &self.buckets);
+        info!("buckets: {:#?}", &buckets);
 
-        // place limit orders on the dips
-        for (i, bucket) in self.buckets.iter().enumerate() {
-            let pct = (i + 1) * self.dip_interval as usize;
-            let price = ask * (1.0 - (pct as f32 / 100.0));
-            let amount = get_amount(price, *bucket);
+        // place limit orders on the dips. a bucket too small to trade is
+        // merged into the next deeper one, a refused or cancelled order's
+        // usd is deferred to the next cycle.
+        let mut carry = Decimal::ZERO;
+        let mut refused = Decimal::ZERO;
+        let mut untracked = vec![];
+        for (i, bucket) in buckets.iter().enumerate() {
+            let pct = self.rungs[i].pct;
+            let price = ask * (Decimal::ONE - pct / Decimal::ONE_HUNDRED);
+            let usd = *bucket + carry;
+            let amount = get_amount(price, usd);
+            if below_min_order_size(dt, amount) {
+                info!(
+                    "{{{}}}: {}% ${:.2} is below the minimum order size {}, merging into next bucket",
+                    symbol, pct, usd, dt.min_order_size
+                );
+                carry = usd;
+                continue;
+            }
+            carry = Decimal::ZERO;
+            let client_order_id = client_order_id(id, &format!("{}", i + 1));
             let status = new_maker_order(
-                &client,
-                &format!("{}", i + 1).to_owned(),
+                client,
+                dt,
+                &client_order_id,
                 symbol,
+                Side::Buy,
                 amount,
                 price,
             );
+            let status = match placed_maker(client, symbol, &client_order_id, orders, status) {
+                Placed::Order(status) => status,
+                Placed::Refused => {
+                    refused += usd;
+                    continue;
+                }
+                // dropped rather than risk buying twice
+                Placed::Unknown => continue,
+            };
             if status.is_cancelled {
                 warn!(
                     "{{{}}}: order was cancelled! ({})",
                     symbol,
                     status.reason.unwrap_or("".to_string())
                 );
+                refused += usd;
             } else {
                 info!(
                     "{{{}}}: {}% order id={} {:.6} @ ${:.2}",
                     symbol, pct, status.order_id, amount, price
                 );
-                let id: u64 = status.order_id.parse().unwrap();
-                orders.push(id);
+                match status.order_id.parse() {
+                    Ok(order_id) => orders.push(order_id),
+                    Err(_) => untracked.push(status.order_id),
+                }
             }
             thread::sleep(Duration::from_millis(10))
         }
+        if carry > Decimal::ZERO {
+            info!(
+                "{{{}}}: deferring ${:.2} below the minimum order size to next cycle",
+                symbol, carry
+            );
+        }
+        if refused > Decimal::ZERO {
+            info!(
+                "{{{}}}: deferring ${:.2} of refused orders to next cycle",
+                symbol, refused
+            );
+        }
+        self.deferred += carry + refused;
         debug!("{{{}}}: order_ids: {:?}", symbol, orders);
-        true
+        if !untracked.is_empty() {
+            return Err(Error::Parse(format!(
+                "the ids {:?} of live orders, they aren't tracked",
+                untracked
+            )));
+        }
+        Ok(())
+    }
+
+    fn wake_in(&self) -> Option<Duration> {
+        if self.pending_market > Decimal::ZERO {
+            self.market.retry
+        } else {
+            None
+        }
+    }
+
+    fn follow_up(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        _orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        let mut usd = self.pending_market;
+        if let Some(limit) = self.limit.take() {
+            usd = usd.min(limit);
+        }
+        info!("{{{}}}: retrying market order ${:.2}", symbol, usd);
+        // what was short of funds or didn't fill waits for the next retry
+        let unfilled = self.market_order(client, id, symbol, dt, tk.ask, usd);
+        self.pending_market -= usd - unfilled;
+        if unfilled > Decimal::ZERO {
+            info!("{{{}}}: market order cancelled again", symbol);
+        }
+        Ok(())
+    }
+
+    fn usd_needed(&self, _tk: &Ticker, follow_up: bool) -> Decimal {
+        if follow_up {
+            return self.pending_market;
+        }
+        let window = self.rung_usd.iter().sum::<Decimal>() + self.market_usd;
+        window * Decimal::from(self.missed + 1) + self.deferred + self.pending_market
+    }
+
+    fn limit_usd(&mut self, usd: Decimal) {
+        self.limit = Some(usd);
+    }
+
+    fn carry_missed(&mut self, missed: u32) {
+        self.missed = missed;
+    }
+
+    fn save_state(&self) -> Value {
+        json!(DipState {
+            deferred: self.deferred,
+            pending_market: self.pending_market,
+            position: self.position,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: DipState = strat_state(state);
+        self.deferred = state.deferred;
+        self.pending_market = state.pending_market;
+        self.position = state.position;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use chrono::{Duration as cDuration, TimeZone, Utc};
+
+    fn sim(prices: &[i64], usd: i64) -> SimExchange {
+        let prices: Vec<Decimal> = prices.iter().map(|p| Decimal::from(*p)).collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, cDuration::hours(1), &prices);
+        sim.fund("USD", Decimal::from(usd));
+        sim
+    }
+
+    // $150 for the market order and each of the rungs at 5% and 10%
+    fn strat(retry: Option<Duration>) -> DipStrat {
+        let rungs = ladder(Decimal::from(5), Decimal::from(10), &Weighting::Flat).unwrap();
+        let market = MarketBuy {
+            enabled: true,
+            pct: None,
+            retry,
+        };
+        DipStrat::new(Decimal::from(300), rungs, market)
+    }
+
+    fn execute(
+        strat: &mut DipStrat,
+        sim: &SimExchange,
+        orders: &mut Vec<u64>,
+    ) -> Result<(), Error> {
+        let dt = sim.symbol_detail("btcusd")?;
+        let tk = sim.ticker("btcusd")?;
+        strat.execute(sim, "dips_btcusd", "btcusd", orders, &dt, &tk)
+    }
+
+    // the usd of each order, to the cent
+    fn order_usd(sim: &SimExchange, orders: &[u64]) -> Vec<Decimal> {
+        orders
+            .iter()
+            .map(|id| {
+                let status = sim.order_status(*id).unwrap();
+                (status.original_amount * status.price).round_dp(2)
+            })
+            .collect()
+    }
+
+    fn bought(sim: &SimExchange) -> Decimal {
+        sim.fills().iter().map(|f| f.amount).sum()
+    }
+
+    #[test]
+    fn unfilled_rung_is_carried_into_its_next_order() {
+        let mut sim = sim(&[100, 94, 94], 1000);
+        let mut strat = strat(None);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(order_usd(&sim, &orders), vec![Decimal::from(150); 2]);
+
+        // the 5% rung at $95 fills, the 10% one doesn't
+        sim.advance();
+        let filled = orders[0];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        let fill_ids: Vec<u64> = sim.fills().iter().map(|f| f.order_id).collect();
+        assert_eq!(fill_ids, vec![1, filled, 4]);
+        assert_eq!(
+            order_usd(&sim, &orders),
+            vec![Decimal::from(150), Decimal::from(300)]
+        );
+        assert_eq!(strat.position.amount, bought(&sim));
+        assert_eq!(strat.deferred, Decimal::ZERO);
+    }
+
+    #[test]
+    fn order_the_exchange_no_longer_knows_is_dropped() {
+        let sim = sim(&[100], 1000);
+        let mut strat = strat(None);
+        let mut orders = vec![999];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert!(!orders.contains(&999));
+        assert_eq!(orders.len(), 2);
+    }
+
+    #[test]
+    fn would_post_market_order_is_retried() {
+        let mut sim = sim(&[100], 1000);
+        let mut strat = strat(Some(Duration::from_secs(60)));
+        sim.reject_next("ImmediateOrCancelWouldPost");
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(strat.pending_market, Decimal::from(150));
+        assert_eq!(strat.wake_in(), Some(Duration::from_secs(60)));
+        assert_eq!(orders.len(), 2);
+        assert!(sim.fills().is_empty());
+
+        let dt = sim.symbol_detail("btcusd").unwrap();
+        let tk = sim.ticker("btcusd").unwrap();
+        strat
+            .follow_up(&sim, "dips_btcusd", "btcusd", &mut orders, &dt, &tk)
+            .unwrap();
+        assert_eq!(strat.pending_market, Decimal::ZERO);
+        assert_eq!(strat.wake_in(), None);
+        assert_eq!(bought(&sim), Decimal::new(15, 1));
+    }
+
+    #[test]
+    fn would_post_market_order_goes_to_the_first_rung() {
+        let mut sim = sim(&[100], 1000);
+        let mut strat = strat(None);
+        sim.reject_next("ImmediateOrCancelWouldPost");
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(
+            order_usd(&sim, &orders),
+            vec![Decimal::from(300), Decimal::from(150)]
+        );
+    }
+
+    #[test]
+    fn partly_filled_market_order_carries_the_rest() {
+        let mut sim = sim(&[100], 1000);
+        sim.liquidity = Some(Decimal::new(5, 1));
+        let mut strat = strat(None);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(bought(&sim), Decimal::new(5, 1));
+        assert_eq!(strat.position.amount, Decimal::new(5, 1));
+        assert_eq!(
+            order_usd(&sim, &orders),
+            vec![Decimal::from(250), Decimal::from(150)]
+        );
+    }
+
+    #[test]
+    fn partly_filled_market_order_retries_the_rest() {
+        let mut sim = sim(&[100], 1000);
+        sim.liquidity = Some(Decimal::new(5, 1));
+        let mut strat = strat(Some(Duration::from_secs(60)));
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(strat.pending_market.round_dp(2), Decimal::from(100));
+    }
+
+    #[test]
+    fn refused_rung_is_deferred() {
+        // enough for the market order and the first rung only
+        let sim = sim(&[100], 300);
+        let mut strat = strat(None);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(order_usd(&sim, &orders), vec![Decimal::from(150)]);
+        assert_eq!(strat.deferred, Decimal::from(150));
     }
 }
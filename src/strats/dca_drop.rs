@@ -0,0 +1,254 @@
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cfg::StratDrop;
+use crate::error::Error;
+use crate::exchange::market_data::SharedBook;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
+
+use crate::strats::order_util;
+use crate::strats::state::{strat_state, Position};
+use crate::strats::strat;
+use crate::strats::strat_util;
+use order_util::{below_min_order_size, client_order_id, new_taker_order, Side};
+use strat::Execute;
+use strat_util::*;
+
+/// Buys `usd_per_drop` whenever the market data feed sees the price fall
+/// `drop_pct` below its high of the last `drop_window`, out of a budget
+/// topped up by `usd_per_window` on each scheduled execution.
+pub struct DropStrat {
+    pub usd_per_window: Decimal,
+    usd_per_drop: Decimal,
+    drop_pct: Decimal,
+    drop_window: Duration,
+    // no drop is bought until this long after the last one
+    cooldown: Duration,
+    book: SharedBook,
+    // usd of the windows not spent on drops yet
+    budget: Decimal,
+    last_buy: Option<DateTime<Utc>>,
+    // cap on the usd of the next buy when funds are short
+    limit: Option<Decimal>,
+    position: Position,
+}
+
+impl DropStrat {
+    pub fn new(
+        usd_per_window: Decimal,
+        usd_per_drop: Decimal,
+        drop_pct: Decimal,
+        drop_window: Duration,
+        cooldown: Duration,
+        book: SharedBook,
+    ) -> DropStrat {
+        DropStrat {
+            usd_per_window,
+            usd_per_drop,
+            drop_pct,
+            drop_window,
+            cooldown,
+            book,
+            budget: Decimal::ZERO,
+            last_buy: None,
+            limit: None,
+            position: Position::default(),
+        }
+    }
+
+    /// The strategy described by a `[[strat_drop]]` table, watching the
+    /// feed of its symbol.
+    pub fn from_cfg(
+        usd_per_window: Decimal,
+        cfg: &StratDrop,
+        book: SharedBook,
+    ) -> Result<DropStrat, String> {
+        if cfg.drop_pct <= Decimal::ZERO || cfg.drop_pct >= Decimal::ONE_HUNDRED {
+            return Err("drop_pct needs 0 < pct < 100".to_owned());
+        }
+        if cfg.drop_mins == 0 {
+            return Err("drop_mins needs to be at least 1".to_owned());
+        }
+        if cfg.usd_per_drop <= Decimal::ZERO {
+            return Err("usd_per_drop needs to be above 0".to_owned());
+        }
+        let drop_window = Duration::minutes(cfg.drop_mins as i64);
+        Ok(DropStrat::new(
+            usd_per_window,
+            cfg.usd_per_drop,
+            cfg.drop_pct,
+            drop_window,
+            cfg.cooldown_mins
+                .map_or(drop_window, |mins| Duration::minutes(mins as i64)),
+            book,
+        ))
+    }
+
+    // the last trade price and the high it has dropped from, if it has
+    // dropped far enough since the cooldown
+    fn dropped(&self) -> Option<(Decimal, Decimal)> {
+        let book = self.book.lock().unwrap();
+        let last = book.last_trade()?;
+        if let Some(last_buy) = self.last_buy {
+            if last.time < last_buy + self.cooldown {
+                return None;
+            }
+        }
+        let high = book.high_since(last.time - self.drop_window)?;
+        let floor = high * (Decimal::ONE - self.drop_pct / Decimal::ONE_HUNDRED);
+        if last.price <= floor {
+            Some((last.price, high))
+        } else {
+            None
+        }
+    }
+}
+
+// the unspent budget, the last buy the cooldown runs from, and what was
+// bought
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct DropState {
+    budget: Decimal,
+    last_buy: Option<DateTime<Utc>>,
+    position: Position,
+}
+
+impl Execute for DropStrat {
+    fn execute(
+        &mut self,
+        _client: &dyn Exchange,
+        _id: &str,
+        symbol: &str,
+        _orders: &mut Vec<u64>,
+        _dt: &SymbolDetail,
+        _tk: &Ticker,
+    ) -> Result<(), Error> {
+        self.budget += self.usd_per_window;
+        info!(
+            "{{{}}}: budget ${:.2} for drops of {}% within {}m",
+            symbol,
+            self.budget,
+            self.drop_pct,
+            self.drop_window.num_minutes()
+        );
+        Ok(())
+    }
+
+    fn triggered(&self) -> bool {
+        self.budget >= self.usd_per_drop && self.dropped().is_some()
+    }
+
+    fn follow_up(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        let limit = self.limit.take();
+        let (price, high) = match self.dropped() {
+            Some(drop) => drop,
+            None => return Ok(()),
+        };
+        let (bid, ask) = {
+            let book = self.book.lock().unwrap();
+            // whatever happens, wait out the cooldown before the next one
+            self.last_buy = book.last_trade().map(|t| t.time);
+            (book.best_bid(), book.best_ask())
+        };
+        info!(
+            "{{{}}}: price ${:.2} dropped from ${:.2} in the last {}m, book ${:.2}/${:.2}",
+            symbol,
+            price,
+            high,
+            self.drop_window.num_minutes(),
+            bid.unwrap_or_default(),
+            ask.unwrap_or_default()
+        );
+
+        let mut usd = self.usd_per_drop.min(self.budget);
+        if let Some(limit) = limit {
+            usd = usd.min(limit);
+        }
+        let amount = get_amount(tk.ask, usd);
+        if below_min_order_size(dt, amount) {
+            info!(
+                "{{{}}}: ${:.2} is below the minimum order size {}",
+                symbol, usd, dt.min_order_size
+            );
+            return Ok(());
+        }
+        let status = new_taker_order(
+            client,
+            dt,
+            &client_order_id(id, "drop"),
+            symbol,
+            Side::Buy,
+            amount,
+            tk.ask,
+        )?;
+        if status.is_cancelled && status.executed_amount.is_zero() {
+            warn!(
+                "{{{}}}: drop order cancelled ({})",
+                symbol,
+                status.reason.unwrap_or_default()
+            );
+            return Ok(());
+        }
+        self.position.add_fill(&status);
+        let spent = get_usd(status.executed_amount, status.avg_execution_price);
+        self.budget -= spent.min(self.budget);
+        info!(
+            "{{{}}}: bought {:.6} @ ${:.2} on the drop, budget ${:.2} left",
+            symbol, status.executed_amount, status.avg_execution_price, self.budget
+        );
+        if let Ok(order_id) = status.order_id.parse() {
+            orders.clear();
+            orders.push(order_id);
+        }
+        Ok(())
+    }
+
+    fn usd_needed(&self, _tk: &Ticker, follow_up: bool) -> Decimal {
+        if follow_up {
+            self.usd_per_drop.min(self.budget)
+        } else {
+            Decimal::ZERO
+        }
+    }
+
+    fn limit_usd(&mut self, usd: Decimal) {
+        self.limit = Some(usd);
+    }
+
+    // only the next drop's usd is held back from the other strats, the
+    // rest of the budget may wait for a drop for a long time
+    fn usd_reserved(&self) -> Decimal {
+        self.usd_per_drop.min(self.budget)
+    }
+
+    fn carry_missed(&mut self, missed: u32) {
+        self.budget += self.usd_per_window * Decimal::from(missed);
+    }
+
+    fn save_state(&self) -> Value {
+        json!(DropState {
+            budget: self.budget,
+            last_buy: self.last_buy,
+            position: self.position,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: DropState = strat_state(state);
+        self.budget = state.budget;
+        self.last_buy = state.last_buy;
+        self.position = state.position;
+    }
+}
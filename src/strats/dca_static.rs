@@ -1,56 +1,223 @@
-use log::{info, warn};
+use log::info;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use gemini_rust::{GeminiClient, SymbolDetail, Ticker};
+use crate::cfg::StratStatic;
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
 
+use crate::strats::indicators::{multiplier, rules_from_cfg, Rule};
 use crate::strats::order_util;
+use crate::strats::state::{strat_state, Position};
 use crate::strats::strat;
 use crate::strats::strat_util;
-use order_util::new_taker_order;
+use order_util::{below_min_order_size, client_order_id, new_taker_order, Side};
 use strat::Execute;
 use strat_util::*;
 
 pub struct StaticStrat {
-    pub usd_per_trade: f32,
+    pub usd_per_trade: Decimal,
+    // scale usd_per_trade by the first that matches
+    rules: Vec<Rule>,
+    // usd too small to trade, carried into the next cycle
+    deferred: Decimal,
+    // cap on the usd of the next execution when funds are short
+    limit: Option<Decimal>,
+    position: Position,
 }
 
 impl StaticStrat {
-    pub fn new(usd_per_trade: f32) -> StaticStrat {
-        StaticStrat { usd_per_trade }
+    pub fn new(usd_per_trade: Decimal, rules: Vec<Rule>) -> StaticStrat {
+        StaticStrat {
+            usd_per_trade,
+            rules,
+            deferred: Decimal::ZERO,
+            limit: None,
+            position: Position::default(),
+        }
+    }
+
+    /// The strategy described by a `[[strat_static]]` table.
+    pub fn from_cfg(usd_per_trade: Decimal, cfg: &StratStatic) -> Result<StaticStrat, String> {
+        Ok(StaticStrat::new(
+            usd_per_trade,
+            rules_from_cfg(&cfg.multipliers)?,
+        ))
     }
 }
 
+// usd deferred from a run too small to trade, and what was bought
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StaticState {
+    deferred: Decimal,
+    position: Position,
+}
+
 impl Execute for StaticStrat {
     fn execute(
         &mut self,
-        client: &GeminiClient,
+        client: &dyn Exchange,
+        id: &str,
         symbol: &str,
         orders: &mut Vec<u64>,
-        _dt: &SymbolDetail,
+        dt: &SymbolDetail,
         tk: &Ticker,
-    ) -> bool {
-        let ask: f32 = tk.ask.parse().unwrap();
+    ) -> Result<(), Error> {
+        let ask = tk.ask;
 
-        let amount = get_amount(ask, self.usd_per_trade);
-        let order_status = new_taker_order(&client, "", symbol, amount, ask);
+        let mut usd_per_trade = self.usd_per_trade;
+        if !self.rules.is_empty() {
+            let mult = multiplier(client, symbol, tk.last, &self.rules);
+            usd_per_trade *= mult;
+            info!(
+                "{{{}}}: multiplier {}x, buying ${:.2}",
+                symbol, mult, usd_per_trade
+            );
+        }
+        let mut usd = usd_per_trade + self.deferred;
+        if let Some(limit) = self.limit.take() {
+            usd = usd.min(limit);
+        }
+        let amount = get_amount(ask, usd);
+        if below_min_order_size(dt, amount) {
+            info!(
+                "{{{}}}: ${:.2} is below the minimum order size {}, deferring to next cycle",
+                symbol, usd, dt.min_order_size
+            );
+            self.deferred = usd;
+            return Ok(());
+        }
+        let order_status = new_taker_order(
+            client,
+            dt,
+            &client_order_id(id, "market"),
+            symbol,
+            Side::Buy,
+            amount,
+            ask,
+        )?;
 
         if order_status.is_cancelled
             && order_status.reason == Some("ImmediateOrCancelWouldPost".to_owned())
         {
-            warn!(
-                "{{{}}}: order cancelled as would post, retry in 15s",
-                symbol
-            );
-            return false;
+            return Err(Error::Rejected("order cancelled as would post".to_owned()));
         }
         info!(
             "{{{}}}: limit order id={} {:.6} @ ${:.2}",
             symbol, order_status.order_id, amount, ask
         );
-        if orders.len() == 0 {
-            orders.push(order_status.order_id.parse::<u64>().unwrap());
-        } else {
-            orders[0] = order_status.order_id.parse::<u64>().unwrap();
+        self.deferred = Decimal::ZERO;
+        self.position.add_fill(&order_status);
+        if let Ok(order_id) = order_status.order_id.parse::<u64>() {
+            if orders.is_empty() {
+                orders.push(order_id);
+            } else {
+                orders[0] = order_id;
+            }
         }
-        return true;
+        Ok(())
+    }
+
+    fn usd_needed(&self, _tk: &Ticker, _follow_up: bool) -> Decimal {
+        // the multiplier isn't known until the candles are fetched, so
+        // allow for the largest
+        let mult = self
+            .rules
+            .iter()
+            .map(|r| r.multiplier)
+            .fold(Decimal::ONE, Decimal::max);
+        self.usd_per_trade * mult + self.deferred
+    }
+
+    fn limit_usd(&mut self, usd: Decimal) {
+        self.limit = Some(usd);
+    }
+
+    fn carry_missed(&mut self, missed: u32) {
+        self.deferred += self.usd_per_trade * Decimal::from(missed);
+    }
+
+    fn save_state(&self) -> Value {
+        json!(StaticState {
+            deferred: self.deferred,
+            position: self.position,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: StaticState = strat_state(state);
+        self.deferred = state.deferred;
+        self.position = state.position;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn sim(prices: &[i64]) -> SimExchange {
+        let prices: Vec<Decimal> = prices.iter().map(|p| Decimal::from(*p)).collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, Duration::hours(1), &prices);
+        sim.fund("USD", Decimal::from(1000));
+        sim
+    }
+
+    fn execute(
+        strat: &mut StaticStrat,
+        sim: &SimExchange,
+        orders: &mut Vec<u64>,
+    ) -> Result<(), Error> {
+        let dt = sim.symbol_detail("btcusd")?;
+        let tk = sim.ticker("btcusd")?;
+        strat.execute(sim, "static_btcusd", "btcusd", orders, &dt, &tk)
+    }
+
+    #[test]
+    fn buys_at_the_ask_each_run() {
+        let mut sim = sim(&[100, 50]);
+        let mut strat = StaticStrat::new(Decimal::from(100), vec![]);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        sim.advance();
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        let fills: Vec<(u64, Decimal)> =
+            sim.fills().iter().map(|f| (f.order_id, f.price)).collect();
+        assert_eq!(fills, vec![(1, Decimal::from(100)), (2, Decimal::from(50))]);
+        assert_eq!(orders, vec![2]);
+        assert_eq!(strat.position.amount, Decimal::from(3));
+        assert_eq!(strat.position.cost, Decimal::from(200));
+    }
+
+    #[test]
+    fn would_post_is_rejected_to_be_retried() {
+        let mut sim = sim(&[100]);
+        let mut strat = StaticStrat::new(Decimal::from(100), vec![]);
+        sim.reject_next("ImmediateOrCancelWouldPost");
+        let mut orders = vec![];
+        let result = execute(&mut strat, &sim, &mut orders);
+        assert!(matches!(result, Err(Error::Rejected(_))));
+        assert!(orders.is_empty());
+        assert_eq!(strat.position.amount, Decimal::ZERO);
+
+        // the retry buys it
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(orders, vec![2]);
+        assert_eq!(strat.position.amount, Decimal::ONE);
+    }
+
+    #[test]
+    fn partial_fill_counts_what_was_bought() {
+        let mut sim = sim(&[100]);
+        sim.liquidity = Some(Decimal::new(4, 1));
+        let mut strat = StaticStrat::new(Decimal::from(100), vec![]);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders).unwrap();
+        assert_eq!(strat.position.amount, Decimal::new(4, 1));
+        assert_eq!(strat.position.cost, Decimal::from(40));
     }
 }
@@ -0,0 +1,419 @@
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::cfg::StratTwap;
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
+
+use crate::strats::order_util;
+use crate::strats::state::{strat_state, Position};
+use crate::strats::strat;
+use crate::strats::strat_util;
+use order_util::{
+    below_min_order_size, client_order_id, new_maker_order, new_taker_order, order_statuses,
+    placed_maker, placed_taker, Placed, Side,
+};
+use strat::Execute;
+use strat_util::*;
+
+/// Spends the window's usd in `child_orders` slices spread over `duration`.
+/// Each slice rests as a maker order at the bid, and whatever hasn't filled
+/// by the end of the slice is bought with a taker order at the ask.
+pub struct TwapStrat {
+    pub usd_per_window: Decimal,
+    child_orders: u32,
+    slice_len: Duration,
+    // slices placed so far this window, 0 when idle
+    slice: u32,
+    // usd of the slices not placed yet this window
+    remaining: Decimal,
+    // usd too small to trade, carried into the next window
+    deferred: Decimal,
+    // cap on the usd of the next execution when funds are short
+    limit: Option<Decimal>,
+    position: Position,
+}
+
+impl TwapStrat {
+    pub fn new(usd_per_window: Decimal, child_orders: u32, duration: Duration) -> TwapStrat {
+        TwapStrat {
+            usd_per_window,
+            child_orders,
+            slice_len: duration / child_orders,
+            slice: 0,
+            remaining: Decimal::ZERO,
+            deferred: Decimal::ZERO,
+            limit: None,
+            position: Position::default(),
+        }
+    }
+
+    /// The strategy described by a `[[strat_twap]]` table.
+    pub fn from_cfg(usd_per_window: Decimal, cfg: &StratTwap) -> Result<TwapStrat, String> {
+        let child_orders = cfg.child_orders.unwrap_or(4);
+        let duration_mins = cfg.duration_mins.unwrap_or(60);
+        if child_orders == 0 || duration_mins == 0 {
+            return Err("twap needs child_orders and duration_mins of at least 1".to_owned());
+        }
+        Ok(TwapStrat::new(
+            usd_per_window,
+            child_orders,
+            Duration::from_secs(duration_mins as u64 * 60),
+        ))
+    }
+
+    // rest the next slice at the bid. a slice too small to trade, or one
+    // that would take or is refused, is left for the slices after it.
+    fn place_slice(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        let left = Decimal::from(self.child_orders - self.slice);
+        self.slice += 1;
+        let usd = self.remaining / left;
+        let amount = get_amount(tk.bid, usd);
+        if below_min_order_size(dt, amount) {
+            info!(
+                "{{{}}}: slice {} ${:.2} is below the minimum order size {}, merging into next slice",
+                symbol, self.slice, usd, dt.min_order_size
+            );
+            return Ok(());
+        }
+        let client_order_id = client_order_id(id, &format!("slice{}", self.slice));
+        let status = new_maker_order(
+            client,
+            dt,
+            &client_order_id,
+            symbol,
+            Side::Buy,
+            amount,
+            tk.bid,
+        );
+        let status = match placed_maker(client, symbol, &client_order_id, orders, status) {
+            Placed::Order(status) => status,
+            Placed::Refused => return Ok(()),
+            // dropped rather than risk buying twice
+            Placed::Unknown => {
+                self.remaining -= usd;
+                return Ok(());
+            }
+        };
+        if status.is_cancelled {
+            warn!(
+                "{{{}}}: slice {} order was cancelled! ({})",
+                symbol,
+                self.slice,
+                status.reason.unwrap_or_default()
+            );
+            return Ok(());
+        }
+        info!(
+            "{{{}}}: slice {}/{} order id={} {:.6} @ ${:.2}",
+            symbol, self.slice, self.child_orders, status.order_id, amount, tk.bid
+        );
+        self.remaining -= usd;
+        let order_id = status.order_id.parse().map_err(|_| {
+            Error::Parse(format!(
+                "the id {:?} of a live order, it isn't tracked",
+                status.order_id
+            ))
+        })?;
+        orders.push(order_id);
+        Ok(())
+    }
+
+    // cancel what is left of the slice orders and return its usd. orders
+    // whose cancel didn't go through are kept to be checked on next time.
+    fn cancel_slices(
+        &mut self,
+        client: &dyn Exchange,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+    ) -> Result<Decimal, Error> {
+        let statuses = order_statuses(client, symbol, orders)?;
+        let mut unfilled = Decimal::ZERO;
+        let mut unsettled = vec![];
+        for (order_id, status) in statuses {
+            let status = if status.is_live {
+                match client.cancel_order(order_id) {
+                    Ok(status) if status.is_cancelled => status,
+                    Ok(status) => {
+                        error!(
+                            "{{{}}}: error cancelling order id={} ({}), checking on it next time",
+                            symbol,
+                            order_id,
+                            status.reason.unwrap_or_default()
+                        );
+                        unsettled.push(order_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(
+                            "{{{}}}: could not cancel order id={}: {}, checking on it next time",
+                            symbol, order_id, e
+                        );
+                        unsettled.push(order_id);
+                        continue;
+                    }
+                }
+            } else {
+                status
+            };
+            self.position.add_fill(&status);
+            if status.is_cancelled {
+                unfilled += get_usd(status.remaining_amount, status.price);
+            }
+        }
+        *orders = unsettled;
+        Ok(unfilled)
+    }
+}
+
+// how far into the running window it is, the usd left of it and deferred
+// from the last one
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct TwapState {
+    slice: u32,
+    remaining: Decimal,
+    deferred: Decimal,
+    position: Position,
+}
+
+impl Execute for TwapStrat {
+    fn execute(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        // a window still running when the next one starts is folded into it
+        let needed = self.usd_needed(tk, false);
+        let unfilled = self.cancel_slices(client, symbol, orders)?;
+        let unfinished = unfilled + self.remaining;
+        if unfinished > Decimal::ZERO {
+            info!(
+                "{{{}}}: adding ${:.2} unspent from the last window",
+                symbol, unfinished
+            );
+        }
+        // what the cancelled slices held is back in the balance either way
+        self.remaining = match self.limit.take() {
+            Some(limit) => needed.min(limit),
+            None => needed,
+        } + unfilled;
+        self.deferred = Decimal::ZERO;
+        self.slice = 0;
+        self.place_slice(client, id, symbol, orders, dt, tk)
+    }
+
+    fn wake_in(&self) -> Option<Duration> {
+        if self.slice > 0 {
+            Some(self.slice_len)
+        } else {
+            None
+        }
+    }
+
+    fn follow_up(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        // short of funds, the rest of the window shrinks to fit
+        if let Some(limit) = self.limit.take() {
+            let needed = self.usd_needed(tk, true);
+            if limit < needed {
+                self.remaining = self.remaining * limit / needed;
+            }
+        }
+
+        // take what the slice didn't fill, and on the last slice anything
+        // that was never placed
+        let mut usd = self.cancel_slices(client, symbol, orders)?;
+        if self.slice >= self.child_orders {
+            usd += self.remaining;
+            self.remaining = Decimal::ZERO;
+        }
+        let amount = get_amount(tk.ask, usd);
+        if usd > Decimal::ZERO && !below_min_order_size(dt, amount) {
+            let client_order_id = client_order_id(id, &format!("take{}", self.slice));
+            let status = new_taker_order(
+                client,
+                dt,
+                &client_order_id,
+                symbol,
+                Side::Buy,
+                amount,
+                tk.ask,
+            );
+            // a refused order's usd goes on with the rest
+            match placed_taker(symbol, &client_order_id, status) {
+                Placed::Order(status) => {
+                    self.position.add_fill(&status);
+                    let taken = get_usd(status.executed_amount, status.avg_execution_price);
+                    info!(
+                        "{{{}}}: slice {} took ${:.2} of ${:.2} unfilled @ ${:.2}",
+                        symbol, self.slice, taken, usd, tk.ask
+                    );
+                    usd -= taken.min(usd);
+                }
+                Placed::Refused => {}
+                // dropped rather than risk buying twice
+                Placed::Unknown => usd = Decimal::ZERO,
+            }
+        }
+        if self.slice < self.child_orders {
+            self.remaining += usd;
+            self.place_slice(client, id, symbol, orders, dt, tk)
+        } else {
+            if usd.round_dp(2) > Decimal::ZERO {
+                info!(
+                    "{{{}}}: deferring ${:.2} unspent to the next window",
+                    symbol, usd
+                );
+            }
+            self.deferred += usd;
+            self.slice = 0;
+            Ok(())
+        }
+    }
+
+    fn usd_needed(&self, _tk: &Ticker, follow_up: bool) -> Decimal {
+        if !follow_up {
+            self.usd_per_window + self.deferred + self.remaining
+        } else if self.slice < self.child_orders {
+            // the next slice
+            self.remaining / Decimal::from(self.child_orders - self.slice)
+        } else {
+            self.remaining
+        }
+    }
+
+    fn limit_usd(&mut self, usd: Decimal) {
+        self.limit = Some(usd);
+    }
+
+    fn usd_reserved(&self) -> Decimal {
+        self.remaining
+    }
+
+    fn carry_missed(&mut self, missed: u32) {
+        self.deferred += self.usd_per_window * Decimal::from(missed);
+    }
+
+    fn save_state(&self) -> Value {
+        json!(TwapState {
+            slice: self.slice,
+            remaining: self.remaining,
+            deferred: self.deferred,
+            position: self.position,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: TwapState = strat_state(state);
+        // child_orders may have been lowered since
+        self.slice = state.slice.min(self.child_orders);
+        self.remaining = state.remaining;
+        self.deferred = state.deferred;
+        self.position = state.position;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use chrono::{TimeZone, Utc};
+
+    fn sim() -> SimExchange {
+        let prices = vec![Decimal::ONE_HUNDRED; 2];
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim =
+            SimExchange::from_prices("btcusd", start, chrono::Duration::hours(1), &prices);
+        // a slice at the bid rests below the ask
+        sim.spread = Decimal::new(2, 2);
+        sim.fund("USD", Decimal::from(1000));
+        sim
+    }
+
+    // $300 in two slices of half an hour
+    fn strat() -> TwapStrat {
+        TwapStrat::new(Decimal::from(300), 2, Duration::from_secs(60 * 60))
+    }
+
+    fn run(strat: &mut TwapStrat, sim: &SimExchange, orders: &mut Vec<u64>, follow_up: bool) {
+        let dt = sim.symbol_detail("btcusd").unwrap();
+        let tk = sim.ticker("btcusd").unwrap();
+        let result = match follow_up {
+            true => strat.follow_up(sim, "twap_btcusd", "btcusd", orders, &dt, &tk),
+            false => strat.execute(sim, "twap_btcusd", "btcusd", orders, &dt, &tk),
+        };
+        result.unwrap();
+    }
+
+    fn bought(sim: &SimExchange) -> Decimal {
+        sim.fills().iter().map(|f| f.amount * f.price).sum()
+    }
+
+    #[test]
+    fn unfilled_slices_are_taken_at_the_ask() {
+        let sim = sim();
+        let mut strat = strat();
+        let mut orders = vec![];
+        run(&mut strat, &sim, &mut orders, false);
+        assert_eq!(orders.len(), 1);
+        assert!(sim.order_status(orders[0]).unwrap().is_live);
+        assert_eq!(strat.wake_in(), Some(Duration::from_secs(30 * 60)));
+
+        // the first slice is taken and the second rests
+        run(&mut strat, &sim, &mut orders, true);
+        assert_eq!(sim.fills().len(), 1);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(strat.remaining, Decimal::ZERO);
+
+        // the second is taken and the window ends
+        run(&mut strat, &sim, &mut orders, true);
+        assert_eq!(sim.fills().len(), 2);
+        assert!(orders.is_empty());
+        assert_eq!(strat.wake_in(), None);
+        assert!((bought(&sim) - Decimal::from(300)).abs() < Decimal::ONE);
+        assert_eq!(
+            strat.position.amount,
+            sim.fills().iter().map(|f| f.amount).sum()
+        );
+    }
+
+    #[test]
+    fn window_goes_on_after_a_restart() {
+        let sim = sim();
+        let mut strat = strat();
+        let mut orders = vec![];
+        run(&mut strat, &sim, &mut orders, false);
+
+        let mut restarted = TwapStrat::new(Decimal::from(300), 2, Duration::from_secs(60 * 60));
+        restarted.load_state(&strat.save_state());
+        assert_eq!(restarted.slice, 1);
+        assert_eq!(restarted.wake_in(), Some(Duration::from_secs(30 * 60)));
+        run(&mut restarted, &sim, &mut orders, true);
+        run(&mut restarted, &sim, &mut orders, true);
+        assert_eq!(restarted.wake_in(), None);
+        assert!((bought(&sim) - Decimal::from(300)).abs() < Decimal::ONE);
+    }
+}
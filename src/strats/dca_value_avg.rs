@@ -0,0 +1,255 @@
+use log::info;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cfg::StratValueAvg;
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
+
+use crate::strats::order_util;
+use crate::strats::state::{strat_state, Position};
+use crate::strats::strat;
+use crate::strats::strat_util;
+use order_util::{below_min_order_size, client_order_id, new_taker_order, Side};
+use strat::Execute;
+use strat_util::*;
+
+/// Buys whatever brings the value of the coins it has bought up to a target
+/// that grows by `value_per_window` each window, within `min_usd` and
+/// `max_usd` per window.
+pub struct ValueAvgStrat {
+    pub value_per_window: Decimal,
+    min_usd: Decimal,
+    max_usd: Option<Decimal>,
+    // windows executed so far, the target is this many times value_per_window
+    windows: u32,
+    // coins bought so far
+    position: Position,
+    // cap on the usd of the next execution when funds are short
+    limit: Option<Decimal>,
+}
+
+impl ValueAvgStrat {
+    pub fn new(
+        value_per_window: Decimal,
+        min_usd: Decimal,
+        max_usd: Option<Decimal>,
+    ) -> ValueAvgStrat {
+        ValueAvgStrat {
+            value_per_window,
+            min_usd,
+            max_usd,
+            windows: 0,
+            position: Position::default(),
+            limit: None,
+        }
+    }
+
+    /// The strategy described by a `[[strat_value_avg]]` table, with the
+    /// usd amounts already divided into the share per slice.
+    pub fn from_cfg(
+        cfg: &StratValueAvg,
+        share: impl Fn(Decimal) -> Decimal,
+    ) -> Result<ValueAvgStrat, String> {
+        let min_usd = cfg.min_usd_per_window.unwrap_or(Decimal::ZERO);
+        if min_usd < Decimal::ZERO {
+            return Err("min_usd_per_window can't be negative".to_owned());
+        }
+        if let Some(max_usd) = cfg.max_usd_per_window {
+            if max_usd < min_usd {
+                return Err("max_usd_per_window is below min_usd_per_window".to_owned());
+            }
+        }
+        Ok(ValueAvgStrat::new(
+            share(cfg.value_per_window),
+            share(min_usd),
+            cfg.max_usd_per_window.map(share),
+        ))
+    }
+
+    // the usd to spend to reach the target of `windows` at the given price
+    fn usd_to_target(&self, windows: u32, price: Decimal) -> Decimal {
+        let target = self.value_per_window * Decimal::from(windows);
+        let usd = (target - self.position.amount * price).max(self.min_usd);
+        match self.max_usd {
+            Some(max_usd) => usd.min(max_usd),
+            None => usd,
+        }
+    }
+}
+
+// the windows so far, which set the target value, and what was bought
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ValueAvgState {
+    windows: u32,
+    position: Position,
+}
+
+impl Execute for ValueAvgStrat {
+    fn execute(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        let ask = tk.ask;
+
+        self.windows += 1;
+        // holdings are valued at the last trade
+        let mut usd = self.usd_to_target(self.windows, tk.last);
+        if let Some(limit) = self.limit.take() {
+            usd = usd.min(limit);
+        }
+        info!(
+            "{{{}}}: window {} target ${:.2} holding {} worth ${:.2}, buying ${:.2}",
+            symbol,
+            self.windows,
+            self.value_per_window * Decimal::from(self.windows),
+            self.position.amount,
+            self.position.amount * tk.last,
+            usd
+        );
+        if usd <= Decimal::ZERO {
+            info!("{{{}}}: at or above target, nothing to buy", symbol);
+            return Ok(());
+        }
+        let amount = get_amount(ask, usd);
+        if below_min_order_size(dt, amount) {
+            info!(
+                "{{{}}}: ${:.2} is below the minimum order size {}, nothing to buy",
+                symbol, usd, dt.min_order_size
+            );
+            return Ok(());
+        }
+        let order_status = match new_taker_order(
+            client,
+            dt,
+            &client_order_id(id, "market"),
+            symbol,
+            Side::Buy,
+            amount,
+            ask,
+        ) {
+            Ok(order_status) => order_status,
+            Err(e) => {
+                self.windows -= 1;
+                return Err(e);
+            }
+        };
+
+        if order_status.is_cancelled
+            && order_status.reason == Some("ImmediateOrCancelWouldPost".to_owned())
+        {
+            self.windows -= 1;
+            return Err(Error::Rejected("order cancelled as would post".to_owned()));
+        }
+        self.position.add_fill(&order_status);
+        info!(
+            "{{{}}}: order id={} bought {:.6} @ ${:.2}, holding {}",
+            symbol,
+            order_status.order_id,
+            order_status.executed_amount,
+            order_status.avg_execution_price,
+            self.position.amount
+        );
+        if let Ok(order_id) = order_status.order_id.parse() {
+            orders.clear();
+            orders.push(order_id);
+        }
+        Ok(())
+    }
+
+    fn usd_needed(&self, tk: &Ticker, _follow_up: bool) -> Decimal {
+        self.usd_to_target(self.windows + 1, tk.last)
+    }
+
+    fn limit_usd(&mut self, usd: Decimal) {
+        self.limit = Some(usd);
+    }
+
+    fn carry_missed(&mut self, missed: u32) {
+        self.windows += missed;
+    }
+
+    fn save_state(&self) -> Value {
+        json!(ValueAvgState {
+            windows: self.windows,
+            position: self.position,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: ValueAvgState = strat_state(state);
+        self.windows = state.windows;
+        self.position = state.position;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use chrono::{Duration, TimeZone, Utc};
+
+    // runs a window at each price, returning the usd each one spent
+    fn windows(strat: &mut ValueAvgStrat, prices: &[i64]) -> Vec<Decimal> {
+        let prices: Vec<Decimal> = prices.iter().map(|p| Decimal::from(*p)).collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, Duration::days(1), &prices);
+        sim.fund("USD", Decimal::from(10_000));
+        let mut orders = vec![];
+        let mut spent = vec![];
+        loop {
+            let before: Decimal = sim.fills().iter().map(|f| f.amount * f.price).sum();
+            let dt = sim.symbol_detail("btcusd").unwrap();
+            let tk = sim.ticker("btcusd").unwrap();
+            strat
+                .execute(&sim, "value_avg_btcusd", "btcusd", &mut orders, &dt, &tk)
+                .unwrap();
+            let after: Decimal = sim.fills().iter().map(|f| f.amount * f.price).sum();
+            spent.push((after - before).round_dp(2));
+            if !sim.advance() {
+                return spent;
+            }
+        }
+    }
+
+    fn usd(amounts: &[i64]) -> Vec<Decimal> {
+        amounts.iter().map(|a| Decimal::from(*a)).collect()
+    }
+
+    #[test]
+    fn buys_up_to_the_target() {
+        let mut strat = ValueAvgStrat::new(Decimal::ONE_HUNDRED, Decimal::ZERO, None);
+        // 1 coin at $100, 3 more at $50 to be worth $200, none while 4 are
+        // worth more than $300, then 12 at $25 to be worth $400
+        assert_eq!(
+            windows(&mut strat, &[100, 50, 100, 25]),
+            usd(&[100, 150, 0, 300])
+        );
+        assert_eq!(strat.position.amount, Decimal::from(16));
+        assert_eq!(strat.windows, 4);
+    }
+
+    #[test]
+    fn buys_at_most_max_usd() {
+        let mut strat = ValueAvgStrat::new(
+            Decimal::ONE_HUNDRED,
+            Decimal::ZERO,
+            Some(Decimal::from(120)),
+        );
+        assert_eq!(windows(&mut strat, &[100, 50]), usd(&[100, 120]));
+    }
+
+    #[test]
+    fn buys_at_least_min_usd() {
+        let mut strat = ValueAvgStrat::new(Decimal::ONE_HUNDRED, Decimal::from(20), None);
+        // above the target after the price doubles
+        assert_eq!(windows(&mut strat, &[100, 200]), usd(&[100, 20]));
+    }
+}
@@ -0,0 +1,75 @@
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error;
+use crate::exchange::Exchange;
+
+/// What to do when the available balance is short of what an execution
+/// needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsufficientFunds {
+    // spend what is available
+    Scale,
+    // wait for the next scheduled run
+    Skip,
+    // place the orders regardless and let the exchange reject them
+    Ignore,
+}
+
+impl FromStr for InsufficientFunds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scale" => Ok(InsufficientFunds::Scale),
+            "skip" => Ok(InsufficientFunds::Skip),
+            "ignore" => Ok(InsufficientFunds::Ignore),
+            _ => Err(format!("unknown insufficient_funds policy: {}", s)),
+        }
+    }
+}
+
+/// Usd the strats trading on one account have set aside for later follow
+/// ups, which the exchange isn't holding for an order yet.
+#[derive(Debug, Default)]
+pub struct Reservations {
+    // strat id => usd
+    by_id: HashMap<String, Decimal>,
+}
+
+/// Reservations shared by the strats of an account. They are only read and
+/// updated while holding the account's exchange lock, so a strat's balance
+/// check and the orders it places can't interleave with another's.
+pub type SharedReservations = Arc<Mutex<Reservations>>;
+
+impl Reservations {
+    /// Set the usd reserved by the strat `id`, replacing what it had.
+    pub fn set(&mut self, id: &str, usd: Decimal) {
+        if usd > Decimal::ZERO {
+            self.by_id.insert(id.to_owned(), usd);
+        } else {
+            self.by_id.remove(id);
+        }
+    }
+
+    /// The usd reserved by every strat but `id`.
+    pub fn others(&self, id: &str) -> Decimal {
+        self.by_id
+            .iter()
+            .filter(|(other, _)| *other != id)
+            .map(|(_, usd)| *usd)
+            .sum()
+    }
+}
+
+/// The available balance of `currency`. A currency the account has never
+/// held counts as zero.
+pub fn available_balance(client: &dyn Exchange, currency: &str) -> Result<Decimal, Error> {
+    let balances = client.balances()?;
+    Ok(balances
+        .iter()
+        .find(|b| b.currency.eq_ignore_ascii_case(currency))
+        .map_or(Decimal::ZERO, |b| b.available))
+}
@@ -0,0 +1,213 @@
+use log::warn;
+use rust_decimal::Decimal;
+use std::time::Duration;
+
+use crate::exchange::{frame_duration, Exchange, Ticker};
+use crate::strats::indicators::sma;
+
+/// Checks a ticker looks sane before trading on it. A ticker with a price
+/// of zero or a crossed book never does. With `max_spread_pct` set the
+/// spread can't be wider than that % of the bid, and with
+/// `max_deviation_pct` set the bid, ask and last can't be further than that
+/// % from the moving average of the last `period` candles of `frame`.
+#[derive(Debug, Clone)]
+pub struct PriceGuard {
+    pub max_spread_pct: Option<Decimal>,
+    pub max_deviation_pct: Option<Decimal>,
+    pub frame: String,
+    pub period: usize,
+    // how long to pause trading for once tripped
+    pub pause: Duration,
+}
+
+impl Default for PriceGuard {
+    fn default() -> PriceGuard {
+        PriceGuard {
+            max_spread_pct: None,
+            max_deviation_pct: None,
+            frame: "1hr".to_owned(),
+            period: 24,
+            pause: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+impl PriceGuard {
+    /// The guard described by the price guard options, validated.
+    pub fn new(
+        max_spread_pct: Option<Decimal>,
+        max_deviation_pct: Option<Decimal>,
+        frame: Option<String>,
+        period: Option<u32>,
+        pause_mins: Option<u32>,
+    ) -> Result<PriceGuard, String> {
+        let mut guard = PriceGuard {
+            max_spread_pct,
+            max_deviation_pct,
+            ..Default::default()
+        };
+        for pct in max_spread_pct.iter().chain(max_deviation_pct.iter()) {
+            if *pct <= Decimal::ZERO {
+                return Err("max_spread_pct and max_deviation_pct need to be above 0".to_owned());
+            }
+        }
+        if let Some(frame) = frame {
+            if frame_duration(&frame).is_none() {
+                return Err(format!("unknown candle frame: {}", frame));
+            }
+            guard.frame = frame;
+        }
+        if let Some(period) = period {
+            if period == 0 {
+                return Err("reference_period needs to be at least 1".to_owned());
+            }
+            guard.period = period as usize;
+        }
+        if let Some(mins) = pause_mins {
+            if mins == 0 {
+                return Err("pause_mins needs to be at least 1".to_owned());
+            }
+            guard.pause = Duration::from_secs(mins as u64 * 60);
+        }
+        Ok(guard)
+    }
+
+    /// Why the ticker of `symbol` shouldn't be traded on, if it shouldn't.
+    pub fn check(&self, client: &dyn Exchange, symbol: &str, tk: &Ticker) -> Result<(), String> {
+        if tk.bid <= Decimal::ZERO || tk.ask <= Decimal::ZERO || tk.last <= Decimal::ZERO {
+            return Err(format!(
+                "{} ticker has a price of zero: bid ${} ask ${} last ${}",
+                symbol, tk.bid, tk.ask, tk.last
+            ));
+        }
+        if tk.ask < tk.bid {
+            return Err(format!(
+                "{} book is crossed: bid ${} above ask ${}",
+                symbol, tk.bid, tk.ask
+            ));
+        }
+
+        if let Some(max) = self.max_spread_pct {
+            let spread = (tk.ask - tk.bid) / tk.bid * Decimal::ONE_HUNDRED;
+            if spread > max {
+                return Err(format!(
+                    "{} spread {:.2}% (bid ${} ask ${}) is wider than {}%",
+                    symbol, spread, tk.bid, tk.ask, max
+                ));
+            }
+        }
+
+        if let Some(max) = self.max_deviation_pct {
+            let reference = client
+                .candles(symbol, &self.frame)
+                .ok()
+                .and_then(|candles| sma(&candles, self.period));
+            let reference = match reference {
+                Some(reference) if reference > Decimal::ZERO => reference,
+                _ => {
+                    // not worth stopping the strat for
+                    warn!(
+                        "{{{}}}: not enough {} candles for a reference price, not checking deviation",
+                        symbol, self.frame
+                    );
+                    return Ok(());
+                }
+            };
+            for (name, price) in &[("bid", tk.bid), ("ask", tk.ask), ("last", tk.last)] {
+                let deviation = (*price / reference - Decimal::ONE) * Decimal::ONE_HUNDRED;
+                if deviation.abs() > max {
+                    return Err(format!(
+                        "{} {} ${} is {:.2}% from the {} x {} average ${:.2}, more than {}%",
+                        symbol, name, price, deviation, self.period, self.frame, reference, max
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use chrono::{TimeZone, Utc};
+
+    // a day of hourly candles at $100
+    fn sim() -> SimExchange {
+        let prices = vec![Decimal::ONE_HUNDRED; 24];
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim =
+            SimExchange::from_prices("btcusd", start, chrono::Duration::hours(1), &prices);
+        while sim.advance() {}
+        sim
+    }
+
+    fn ticker(bid: i64, ask: i64, last: i64) -> Ticker {
+        Ticker {
+            bid: Decimal::from(bid),
+            ask: Decimal::from(ask),
+            last: Decimal::from(last),
+        }
+    }
+
+    fn guard() -> PriceGuard {
+        PriceGuard::new(Some(Decimal::ONE), Some(Decimal::from(5)), None, None, None).unwrap()
+    }
+
+    fn paused_for(guard: &PriceGuard, tk: &Ticker) -> Option<String> {
+        guard.check(&sim(), "btcusd", tk).err()
+    }
+
+    #[test]
+    fn sane_ticker_passes() {
+        assert_eq!(paused_for(&guard(), &ticker(100, 100, 100)), None);
+        assert_eq!(paused_for(&guard(), &ticker(100, 101, 104)), None);
+    }
+
+    #[test]
+    fn zero_price_pauses() {
+        for tk in &[
+            ticker(0, 100, 100),
+            ticker(100, 0, 100),
+            ticker(100, 100, 0),
+        ] {
+            let reason = paused_for(&PriceGuard::default(), tk).unwrap();
+            assert!(reason.contains("price of zero"), "{}", reason);
+        }
+    }
+
+    #[test]
+    fn crossed_book_pauses() {
+        let reason = paused_for(&PriceGuard::default(), &ticker(101, 100, 100)).unwrap();
+        assert!(reason.contains("crossed"), "{}", reason);
+    }
+
+    #[test]
+    fn wide_spread_pauses() {
+        let reason = paused_for(&guard(), &ticker(98, 100, 100)).unwrap();
+        assert!(reason.contains("spread"), "{}", reason);
+        // only when the spread is checked
+        assert_eq!(
+            paused_for(&PriceGuard::default(), &ticker(98, 100, 100)),
+            None
+        );
+    }
+
+    #[test]
+    fn price_far_from_the_average_pauses() {
+        let reason = paused_for(&guard(), &ticker(100, 100, 106)).unwrap();
+        assert!(reason.contains("last $106"), "{}", reason);
+        let reason = paused_for(&guard(), &ticker(94, 94, 94)).unwrap();
+        assert!(reason.contains("bid $94"), "{}", reason);
+    }
+
+    #[test]
+    fn deviation_is_not_checked_without_enough_candles() {
+        let guard = PriceGuard {
+            period: 25,
+            ..guard()
+        };
+        assert_eq!(paused_for(&guard, &ticker(150, 150, 150)), None);
+    }
+}
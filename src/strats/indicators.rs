@@ -0,0 +1,290 @@
+use log::{info, warn};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cfg;
+use crate::exchange::{frame_duration, Candle, Exchange};
+
+/// Simple moving average of the last `period` closes.
+pub fn sma(candles: &[Candle], period: usize) -> Option<Decimal> {
+    if period == 0 || candles.len() < period {
+        return None;
+    }
+    let closes = candles[candles.len() - period..].iter().map(|c| c.close);
+    Some(closes.sum::<Decimal>() / Decimal::from(period))
+}
+
+/// Wilder's relative strength index of the closes, 0 to 100.
+pub fn rsi(candles: &[Candle], period: usize) -> Option<Decimal> {
+    if period == 0 || candles.len() <= period {
+        return None;
+    }
+    let n = Decimal::from(period);
+    let mut gain = Decimal::ZERO;
+    let mut loss = Decimal::ZERO;
+    for (i, pair) in candles.windows(2).enumerate() {
+        let change = pair[1].close - pair[0].close;
+        let (up, down) = if change > Decimal::ZERO {
+            (change, Decimal::ZERO)
+        } else {
+            (Decimal::ZERO, -change)
+        };
+        // a plain average over the first period, smoothed after it
+        if i < period {
+            gain += up / n;
+            loss += down / n;
+        } else {
+            gain = (gain * (n - Decimal::ONE) + up) / n;
+            loss = (loss * (n - Decimal::ONE) + down) / n;
+        }
+    }
+    if loss.is_zero() {
+        return Some(Decimal::ONE_HUNDRED);
+    }
+    Some(Decimal::ONE_HUNDRED - Decimal::ONE_HUNDRED / (Decimal::ONE + gain / loss))
+}
+
+/// A market signal computed from candles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Indicator {
+    // % the price is above the moving average, negative when below
+    Sma(usize),
+    Rsi(usize),
+}
+
+impl Indicator {
+    fn value(&self, candles: &[Candle], price: Decimal) -> Option<Decimal> {
+        match *self {
+            Indicator::Sma(period) => {
+                // no average to be above or below when every close is zero
+                sma(candles, period)
+                    .filter(|avg| !avg.is_zero())
+                    .map(|avg| (price / avg - Decimal::ONE) * Decimal::ONE_HUNDRED)
+            }
+            Indicator::Rsi(period) => rsi(candles, period),
+        }
+    }
+}
+
+impl fmt::Display for Indicator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Indicator::Sma(period) => write!(f, "sma({})", period),
+            Indicator::Rsi(period) => write!(f, "rsi({})", period),
+        }
+    }
+}
+
+/// Multiply the usd of a run by `multiplier` when the indicator over the
+/// frame's candles is below `below` and above `above`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub indicator: Indicator,
+    pub frame: String,
+    pub below: Option<Decimal>,
+    pub above: Option<Decimal>,
+    pub multiplier: Decimal,
+}
+
+impl Rule {
+    fn matches(&self, value: Decimal) -> bool {
+        !matches!(self.below, Some(below) if value >= below)
+            && !matches!(self.above, Some(above) if value <= above)
+    }
+}
+
+/// The rules described by the `multipliers` of a strat table.
+pub fn rules_from_cfg(multipliers: &[cfg::Multiplier]) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+    for m in multipliers {
+        if m.period == 0 {
+            return Err("multiplier period needs to be at least 1".to_owned());
+        }
+        let period = m.period as usize;
+        let indicator = match m.indicator.as_str() {
+            "sma" => Indicator::Sma(period),
+            "rsi" => Indicator::Rsi(period),
+            _ => return Err(format!("unknown indicator: {}", m.indicator)),
+        };
+        let frame = m.frame.clone().unwrap_or_else(|| "1day".to_owned());
+        if frame_duration(&frame).is_none() {
+            return Err(format!("unknown candle frame: {}", frame));
+        }
+        if m.below.is_none() && m.above.is_none() {
+            return Err(format!("{} multiplier needs below or above", indicator));
+        }
+        if m.multiplier < Decimal::ZERO {
+            return Err("multiplier can't be negative".to_owned());
+        }
+        rules.push(Rule {
+            indicator,
+            frame,
+            below: m.below,
+            above: m.above,
+            multiplier: m.multiplier,
+        });
+    }
+    Ok(rules)
+}
+
+/// The multiplier of the first rule that matches at `price`, or 1 if none
+/// do. A rule without enough candles to compute its indicator is skipped.
+pub fn multiplier(client: &dyn Exchange, symbol: &str, price: Decimal, rules: &[Rule]) -> Decimal {
+    let mut candles: HashMap<&str, Option<Vec<Candle>>> = HashMap::new();
+    for rule in rules {
+        let value = candles
+            .entry(&rule.frame)
+            .or_insert_with(|| client.candles(symbol, &rule.frame).ok())
+            .as_deref()
+            .and_then(|c| rule.indicator.value(c, price));
+        let value = match value {
+            Some(value) => value,
+            None => {
+                warn!(
+                    "{{{}}}: not enough {} candles for {}, skipping",
+                    symbol, rule.frame, rule.indicator
+                );
+                continue;
+            }
+        };
+        info!(
+            "{{{}}}: {} {} is {:.2}",
+            symbol, rule.frame, rule.indicator, value
+        );
+        if rule.matches(value) {
+            return rule.multiplier;
+        }
+    }
+    Decimal::ONE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn candles(closes: &[i64]) -> Vec<Candle> {
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        closes
+            .iter()
+            .enumerate()
+            .map(|(i, close)| Candle {
+                time: start + Duration::hours(i as i64),
+                high: Decimal::from(*close),
+                low: Decimal::from(*close),
+                close: Decimal::from(*close),
+                volume: Decimal::ZERO,
+            })
+            .collect()
+    }
+
+    fn rule(indicator: Indicator, below: Option<i64>, above: Option<i64>, multiplier: i64) -> Rule {
+        Rule {
+            indicator,
+            frame: "1hr".to_owned(),
+            below: below.map(Decimal::from),
+            above: above.map(Decimal::from),
+            multiplier: Decimal::from(multiplier),
+        }
+    }
+
+    #[test]
+    fn sma_of_the_last_period_closes() {
+        let table: &[(&[i64], usize, Option<Decimal>)] = &[
+            (&[1, 2, 3, 4], 2, Some(Decimal::new(35, 1))),
+            (&[1, 2, 3, 4], 4, Some(Decimal::new(25, 1))),
+            (&[7], 1, Some(Decimal::from(7))),
+            (&[1, 2, 3, 4], 5, None),
+            (&[1, 2, 3, 4], 0, None),
+        ];
+        for (closes, period, expected) in table {
+            assert_eq!(sma(&candles(closes), *period), *expected, "{:?}", closes);
+        }
+    }
+
+    #[test]
+    fn rsi_of_known_series() {
+        let table: &[(&[i64], usize, Option<Decimal>)] = &[
+            // smoothed gain 0.75 and loss 0.25 after the first period
+            (&[1, 2, 3, 2, 3], 2, Some(Decimal::from(75))),
+            (&[1, 2, 3], 2, Some(Decimal::ONE_HUNDRED)),
+            (&[3, 2, 1], 2, Some(Decimal::ZERO)),
+            (&[1, 3, 1], 2, Some(Decimal::from(50))),
+            (&[1, 2], 2, None),
+            (&[1, 2, 3], 0, None),
+        ];
+        for (closes, period, expected) in table {
+            assert_eq!(rsi(&candles(closes), *period), *expected, "{:?}", closes);
+        }
+    }
+
+    #[test]
+    fn sma_indicator_is_the_pct_from_the_average() {
+        let table: &[(&[i64], i64, Option<Decimal>)] = &[
+            (&[100, 100], 110, Some(Decimal::from(10))),
+            (&[100, 100], 80, Some(Decimal::from(-20))),
+            (&[0, 0], 80, None),
+        ];
+        for (closes, price, expected) in table {
+            let value = Indicator::Sma(2).value(&candles(closes), Decimal::from(*price));
+            assert_eq!(value, *expected, "{:?} @ {}", closes, price);
+        }
+    }
+
+    #[test]
+    fn rule_matches_strictly_between_its_bounds() {
+        let table = [
+            (Some(30), None, 29, true),
+            (Some(30), None, 30, false),
+            (None, Some(70), 71, true),
+            (None, Some(70), 70, false),
+            (Some(10), Some(-10), 0, true),
+            (Some(10), Some(-10), 10, false),
+            (Some(10), Some(-10), -10, false),
+        ];
+        for (below, above, value, expected) in table {
+            let rule = rule(Indicator::Rsi(14), below, above, 2);
+            assert_eq!(
+                rule.matches(Decimal::from(value)),
+                expected,
+                "{:?} {:?} {}",
+                below,
+                above,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn multiplier_of_the_first_matching_rule() {
+        let prices: Vec<Decimal> = [100, 100, 100, 80]
+            .iter()
+            .map(|p| Decimal::from(*p))
+            .collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, Duration::hours(1), &prices);
+        while sim.advance() {}
+
+        // the sma(4) is 95, so 80 is 15.8% below it and 100 5.3% above
+        let rules = [
+            rule(Indicator::Sma(10), Some(0), None, 5),
+            rule(Indicator::Sma(4), Some(-10), None, 2),
+            rule(Indicator::Sma(4), None, Some(5), 0),
+        ];
+        let table = [
+            (80, Decimal::from(2)),
+            (100, Decimal::ZERO),
+            (96, Decimal::ONE),
+        ];
+        for (price, expected) in table {
+            assert_eq!(
+                multiplier(&sim, "btcusd", Decimal::from(price), &rules),
+                expected,
+                "@ {}",
+                price
+            );
+        }
+    }
+}
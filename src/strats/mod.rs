@@ -1,5 +1,16 @@
+pub mod clock;
 pub mod dca_dips;
+pub mod dca_drop;
 pub mod dca_static;
-mod order_util;
+pub mod dca_twap;
+pub mod dca_value_avg;
+pub mod funds;
+pub mod guard;
+pub mod indicators;
+pub mod order_util;
+pub mod rebalance;
+pub mod schedule;
+pub mod state;
 pub mod strat;
 mod strat_util;
+pub mod take_profit;
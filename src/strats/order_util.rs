@@ -1,50 +1,163 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
 use log::{debug, error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-use gemini_rust::{GeminiClient, OrderPlacerAPI, OrderStatus, OrderStatusAPI};
+use crate::error::Error;
+use crate::exchange::{Balance, Candle, Exchange, OrderStatus, SymbolDetail, Ticker};
+use crate::strats::schedule::{midnight, resolve_local};
 
 use crate::strats::strat_util;
 use strat_util::get_usd;
 
+/// Tag an order with the id of the strat instance that placed it, so it can
+/// be matched back to that instance after a restart.
+pub fn client_order_id(id: &str, tag: &str) -> String {
+    format!("{}:{}", id, tag)
+}
+
+/// Split a client order id into the strat instance id and the strat's tag.
+pub fn split_client_order_id(client_order_id: &str) -> (&str, &str) {
+    match client_order_id.rfind(':') {
+        Some(i) => (&client_order_id[..i], &client_order_id[i + 1..]),
+        None => ("", client_order_id),
+    }
+}
+
+/// Which side of the book an order is on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+impl FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            _ => Err(format!("unknown side: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// round down to a multiple of the increment
+fn snap(value: Decimal, increment: Decimal) -> Decimal {
+    if increment.is_zero() {
+        return value;
+    }
+    (value / increment).floor() * increment
+}
+
+/// Round a price to the symbol's quote increment, down for buys and up for
+/// sells so the order is never worse than asked for.
+pub fn snap_price(dt: &SymbolDetail, side: Side, price: Decimal) -> Decimal {
+    match side {
+        Side::Buy => snap(price, dt.quote_increment),
+        Side::Sell if dt.quote_increment.is_zero() => price,
+        Side::Sell => (price / dt.quote_increment).ceil() * dt.quote_increment,
+    }
+}
+
+/// Round an amount down to the symbol's tick size.
+pub fn snap_amount(dt: &SymbolDetail, amount: Decimal) -> Decimal {
+    snap(amount, dt.tick_size)
+}
+
+/// True if the amount, once snapped to the tick size, is too small for the
+/// exchange to accept.
+pub fn below_min_order_size(dt: &SymbolDetail, amount: Decimal) -> bool {
+    snap_amount(dt, amount) < dt.min_order_size
+}
+
+#[allow(clippy::too_many_arguments)]
 fn new_order(
-    client: &GeminiClient,
+    client: &dyn Exchange,
+    dt: &SymbolDetail,
     order_id: &str,
     type_: &str,
     symbol: &str,
-    amount: f32,
-    price: f32,
-) -> OrderStatus {
-    let os = client.new_order(
-        symbol,
-        amount,
-        price,
-        "buy",
-        "exchange limit",
-        order_id,
-        &vec![type_],
-    );
-    if os.is_none() {
-        error!(
-            "{{{}}}: {} order {} failed ${:.2} {:.6} @ ${}",
+    side: Side,
+    amount: Decimal,
+    price: Decimal,
+) -> Result<OrderStatus, Error> {
+    let amount = snap_amount(dt, amount);
+    let price = snap_price(dt, side, price);
+    if amount < dt.min_order_size {
+        warn!(
+            "{{{}}}: {} {} order {} ${:.2} {:.8} @ ${} is below the minimum order size {}",
             symbol,
             type_,
+            side,
             order_id,
             get_usd(amount, price),
             amount,
             price,
+            dt.min_order_size,
         );
-        let mut r = OrderStatus::default();
-        r.is_cancelled = true;
-        return r;
+        return Err(Error::Rejected(format!(
+            "BelowMinOrderSize {} {}",
+            amount, dt.min_order_size
+        )));
     }
-    let order_status = os.unwrap();
+
+    let os = client.new_order(
+        symbol,
+        amount,
+        price,
+        side.as_str(),
+        "exchange limit",
+        order_id,
+        &[type_],
+    );
+    let order_status = match os {
+        Ok(order_status) => order_status,
+        Err(e) => {
+            error!(
+                "{{{}}}: {} {} order {} failed ${:.2} {:.6} @ ${}: {}",
+                symbol,
+                type_,
+                side,
+                order_id,
+                get_usd(amount, price),
+                amount,
+                price,
+                e,
+            );
+            return Err(e);
+        }
+    };
 
     debug!("{{{}}}: {:?}", symbol, order_status);
 
     if order_status.is_cancelled {
         warn!(
-            "{{{}}}: {} order {} cancelled ${:.2} {:.8} @ ${}",
+            "{{{}}}: {} {} order {} cancelled ${:.2} {:.8} @ ${}",
             symbol,
             type_,
+            side,
             order_status.order_id,
             get_usd(amount, price),
             amount,
@@ -53,60 +166,176 @@ fn new_order(
         );
     } else {
         info!(
-            "{{{}}}: {} order {} success ${:.2} {:.8} @ ${}",
+            "{{{}}}: {} {} order {} success ${:.2} {:.8} @ ${}",
             symbol,
             type_,
+            side,
             order_status.order_id,
-            get_usd(
-                order_status.original_amount.parse().unwrap(),
-                order_status.price.parse().unwrap()
-            ),
+            get_usd(order_status.original_amount, order_status.price),
             order_status.original_amount,
             order_status.price,
         );
     }
-    return order_status;
+    Ok(order_status)
 }
 
 pub fn new_maker_order(
-    client: &GeminiClient,
+    client: &dyn Exchange,
+    dt: &SymbolDetail,
     order_id: &str,
     symbol: &str,
-    amount: f32,
-    price: f32,
-) -> OrderStatus {
-    new_order(client, order_id, "maker-or-cancel", symbol, amount, price)
+    side: Side,
+    amount: Decimal,
+    price: Decimal,
+) -> Result<OrderStatus, Error> {
+    new_order(
+        client,
+        dt,
+        order_id,
+        "maker-or-cancel",
+        symbol,
+        side,
+        amount,
+        price,
+    )
 }
 
 pub fn new_taker_order(
-    client: &GeminiClient,
+    client: &dyn Exchange,
+    dt: &SymbolDetail,
     order_id: &str,
     symbol: &str,
-    amount: f32,
-    price: f32,
-) -> OrderStatus {
+    side: Side,
+    amount: Decimal,
+    price: Decimal,
+) -> Result<OrderStatus, Error> {
     new_order(
         client,
+        dt,
         order_id,
         "immediate-or-cancel",
         symbol,
+        side,
         amount,
         price,
     )
 }
 
-pub fn cancel_orders_if_open(client: &GeminiClient, order_ids: &Vec<u64>) -> bool {
-    let mut ret = true;
+/// What became of an order the exchange was asked to place.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Placed {
+    Order(OrderStatus),
+    // the exchange refused it, nothing of it was spent
+    Refused,
+    // the request didn't complete and the order may have gone through
+    Unknown,
+}
+
+/// What became of a maker order from the `result` of placing it. When the
+/// request didn't complete the order is looked up among the live orders by
+/// its client order id, skipping the `known` ones placed before with the
+/// same id, so what made it onto the book isn't placed again.
+pub fn placed_maker(
+    client: &dyn Exchange,
+    symbol: &str,
+    client_order_id: &str,
+    known: &[u64],
+    result: Result<OrderStatus, Error>,
+) -> Placed {
+    match result {
+        Err(Error::Transport(_)) => {}
+        result => return placed_taker(symbol, client_order_id, result),
+    }
+    let active = match client.active_orders() {
+        Ok(active) => active,
+        Err(e) => {
+            error!(
+                "{{{}}}: could not look up order {}: {}, not placing it again",
+                symbol, client_order_id, e
+            );
+            return Placed::Unknown;
+        }
+    };
+    let found = active.into_iter().find(|status| {
+        status.client_order_id.as_deref() == Some(client_order_id)
+            && matches!(status.order_id.parse(), Ok(order_id) if !known.contains(&order_id))
+    });
+    match found {
+        Some(status) => {
+            info!(
+                "{{{}}}: order {} id={} was placed after all",
+                symbol, client_order_id, status.order_id
+            );
+            Placed::Order(status)
+        }
+        None => Placed::Refused,
+    }
+}
+
+/// What became of a taker order from the `result` of placing it. One that
+/// may have filled is never looked up, as it doesn't rest on the book.
+pub fn placed_taker(
+    symbol: &str,
+    client_order_id: &str,
+    result: Result<OrderStatus, Error>,
+) -> Placed {
+    match result {
+        Ok(status) => Placed::Order(status),
+        // logged when placed
+        Err(Error::Rejected(_)) => Placed::Refused,
+        Err(_) => {
+            error!(
+                "{{{}}}: order {} may have been placed, not placing it again",
+                symbol, client_order_id
+            );
+            Placed::Unknown
+        }
+    }
+}
+
+/// The status of each of `order_ids`, all of them fetched before any is acted
+/// on so a failed request leaves nothing half done. An order the exchange
+/// has no good answer for, like one it no longer knows, is dropped.
+pub fn order_statuses(
+    client: &dyn Exchange,
+    symbol: &str,
+    order_ids: &[u64],
+) -> Result<Vec<(u64, OrderStatus)>, Error> {
+    let mut statuses = vec![];
     for order_id in order_ids {
-        let os = client.order_status(Some(*order_id), None);
-        if os.is_none() {
-            warn!("No order status for order_id: {}", order_id);
-            ret = false;
-            continue;
+        match client.order_status(*order_id) {
+            Ok(status) => statuses.push((*order_id, status)),
+            Err(e @ Error::Transport(_)) => return Err(e),
+            Err(e) => warn!("{{{}}}: dropping order id={}: {}", symbol, order_id, e),
         }
-        let order_status = os.unwrap();
+    }
+    Ok(statuses)
+}
+
+pub fn cancel_orders_if_open(client: &dyn Exchange, order_ids: &Vec<u64>) -> bool {
+    let mut ret = true;
+    for order_id in order_ids {
+        let order_status = match client.order_status(*order_id) {
+            Ok(order_status) => order_status,
+            Err(e) => {
+                warn!("No order status for order_id: {}: {}", order_id, e);
+                ret = false;
+                continue;
+            }
+        };
         if order_status.is_live {
-            let status = client.cancel_order(*order_id).unwrap();
+            let status = match client.cancel_order(*order_id) {
+                Ok(status) => status,
+                Err(e) => {
+                    error!(
+                        "{{{}}}: could not cancel order id={}: {}",
+                        order_status.symbol, order_id, e
+                    );
+                    ret = false;
+                    continue;
+                }
+            };
             if !status.is_cancelled {
                 error!(
                     "{{{}}}: error cancelling limit order! ({})",
@@ -126,3 +355,1034 @@ pub fn cancel_orders_if_open(client: &GeminiClient, order_ids: &Vec<u64>) -> boo
     }
     ret
 }
+
+/// What to do with live orders when the process is terminated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shutdown {
+    Cancel,
+    // leave every order resting on the book to be picked up by the next run
+    Leave,
+    // cancel orders that have not filled at all, leave partially filled
+    // orders resting
+    CancelUnfilled,
+}
+
+impl FromStr for Shutdown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cancel" => Ok(Shutdown::Cancel),
+            "leave" => Ok(Shutdown::Leave),
+            "cancel_unfilled" => Ok(Shutdown::CancelUnfilled),
+            _ => Err(format!("unknown shutdown policy: {}", s)),
+        }
+    }
+}
+
+/// Cancel the live orders that have not been filled at all.
+pub fn cancel_orders_if_unfilled(client: &dyn Exchange, order_ids: &Vec<u64>) -> bool {
+    let mut unfilled = vec![];
+    for order_id in order_ids {
+        match client.order_status(*order_id) {
+            Ok(status) => {
+                if status.is_live && status.executed_amount.is_zero() {
+                    unfilled.push(*order_id);
+                } else if status.is_live {
+                    info!(
+                        "{{{}}}: {} order id={} partially filled, leaving it resting",
+                        status.symbol,
+                        status.client_order_id.unwrap_or_else(|| "".to_string()),
+                        status.order_id
+                    );
+                }
+            }
+            Err(e) => warn!("No order status for order_id: {}: {}", order_id, e),
+        }
+    }
+    cancel_orders_if_open(client, &unfilled)
+}
+
+/// What to do on startup with live orders placed by a previous run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reconcile {
+    // track them again as if this run had placed them
+    Adopt,
+    Cancel,
+}
+
+impl FromStr for Reconcile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "adopt" => Ok(Reconcile::Adopt),
+            "cancel" => Ok(Reconcile::Cancel),
+            _ => Err(format!("unknown reconcile policy: {}", s)),
+        }
+    }
+}
+
+/// Match the live orders for the symbol to the strat instance `id` via their
+/// client order id, and adopt them into `order_ids` or cancel them.
+/// Orders placed by other instances or by hand are left alone.
+pub fn reconcile_orders(
+    client: &dyn Exchange,
+    id: &str,
+    symbol: &str,
+    order_ids: &mut Vec<u64>,
+    policy: Reconcile,
+) -> bool {
+    let active = match client.active_orders() {
+        Ok(active) => active,
+        Err(e) => {
+            warn!(
+                "{{{}}}: could not list active orders to reconcile: {}",
+                symbol, e
+            );
+            return false;
+        }
+    };
+
+    let mut ret = true;
+    for status in active {
+        if !status.symbol.eq_ignore_ascii_case(symbol) {
+            continue;
+        }
+        let client_order_id = status.client_order_id.clone().unwrap_or_default();
+        if split_client_order_id(&client_order_id).0 != id {
+            continue;
+        }
+        let order_id: u64 = match status.order_id.parse() {
+            Ok(order_id) => order_id,
+            Err(_) => {
+                warn!(
+                    "{{{}}}: {} order has a bad id {:?}, leaving it alone",
+                    symbol, client_order_id, status.order_id
+                );
+                ret = false;
+                continue;
+            }
+        };
+        if order_ids.contains(&order_id) {
+            continue;
+        }
+        match policy {
+            Reconcile::Adopt => {
+                info!(
+                    "{{{}}}: adopting {} order id={} {} @ ${}",
+                    symbol, client_order_id, order_id, status.remaining_amount, status.price
+                );
+                order_ids.push(order_id);
+            }
+            Reconcile::Cancel => {
+                info!(
+                    "{{{}}}: cancelling {} order id={} left by a previous run",
+                    symbol, client_order_id, order_id
+                );
+                ret &= cancel_orders_if_open(client, &vec![order_id]);
+            }
+        }
+    }
+    ret
+}
+
+/// Hard limits on the usd a strat puts into buy orders, none where there is
+/// no limit.
+#[derive(Debug, Default, Clone)]
+pub struct Caps {
+    pub per_order: Option<Decimal>,
+    pub per_execution: Option<Decimal>,
+    pub per_week: Option<Decimal>,
+    pub per_month: Option<Decimal>,
+    pub lifetime: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Spend {
+    time: DateTime<Utc>,
+    usd: Decimal,
+}
+
+/// The usd a strat has put into buy orders, i.e. what filled plus what is
+/// still live. Kept in its state so the caps hold across restarts.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Spending {
+    // recent orders by order id
+    orders: HashMap<u64, Spend>,
+    // usd of the orders too old to count towards the week or month
+    earlier: Decimal,
+}
+
+impl Spending {
+    fn since(&self, start: DateTime<Utc>) -> Decimal {
+        self.orders
+            .values()
+            .filter(|s| s.time >= start)
+            .map(|s| s.usd)
+            .sum()
+    }
+
+    fn total(&self) -> Decimal {
+        self.earlier + self.orders.values().map(|s| s.usd).sum::<Decimal>()
+    }
+
+    // fold the orders placed before `start` into the earlier total. their
+    // fills aren't followed any further.
+    fn fold(&mut self, start: DateTime<Utc>) {
+        let earlier = &mut self.earlier;
+        self.orders.retain(|_, s| {
+            if s.time < start {
+                *earlier += s.usd;
+            }
+            s.time >= start
+        });
+    }
+}
+
+// the usd an order has filled for plus what is still live of it
+fn committed(status: &OrderStatus) -> Decimal {
+    let mut usd = get_usd(status.executed_amount, status.avg_execution_price);
+    if status.is_live {
+        usd += get_usd(status.remaining_amount, status.price);
+    }
+    usd
+}
+
+// the start of the calendar week, from monday, and month of `now`
+fn period_starts(now: DateTime<Utc>, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    let today = now.with_timezone(&tz).naive_local().date();
+    let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    let first = today - Duration::days(today.day0() as i64);
+    let start = |day: NaiveDate| resolve_local(tz, day.and_time(midnight())).with_timezone(&Utc);
+    (start(monday), start(first))
+}
+
+/// An exchange that refuses buy orders which would break a strat's caps,
+/// and keeps its spending up to date with the orders it sees placed,
+/// checked on or cancelled. One is made for each execution, so every order
+/// the strat places goes through it.
+pub struct Capped<'a> {
+    client: &'a dyn Exchange,
+    caps: &'a Caps,
+    spending: &'a RefCell<Spending>,
+    now: DateTime<Utc>,
+    week: DateTime<Utc>,
+    month: DateTime<Utc>,
+    // orders placed this execution
+    placed: RefCell<Vec<u64>>,
+}
+
+impl<'a> Capped<'a> {
+    pub fn new(
+        client: &'a dyn Exchange,
+        caps: &'a Caps,
+        spending: &'a RefCell<Spending>,
+        now: DateTime<Utc>,
+        tz: Tz,
+    ) -> Capped<'a> {
+        let (week, month) = period_starts(now, tz);
+        spending.borrow_mut().fold(week.min(month));
+        Capped {
+            client,
+            caps,
+            spending,
+            now,
+            week,
+            month,
+            placed: RefCell::new(vec![]),
+        }
+    }
+
+    // the cap `usd` more would break, with the cap and the usd already
+    // counted against it
+    fn broken(&self, usd: Decimal) -> Option<(&'static str, Decimal, Decimal)> {
+        let spending = self.spending.borrow();
+        let execution = self
+            .placed
+            .borrow()
+            .iter()
+            .filter_map(|id| spending.orders.get(id))
+            .map(|s| s.usd)
+            .sum();
+        let caps = [
+            ("order", self.caps.per_order, Decimal::ZERO),
+            ("execution", self.caps.per_execution, execution),
+            ("week", self.caps.per_week, spending.since(self.week)),
+            ("month", self.caps.per_month, spending.since(self.month)),
+            ("lifetime", self.caps.lifetime, spending.total()),
+        ];
+        caps.iter().find_map(|(name, cap, spent)| match cap {
+            Some(cap) if *spent + usd > *cap => Some((*name, *cap, *spent)),
+            _ => None,
+        })
+    }
+
+    // follow what became of an order placed earlier
+    fn update(&self, status: &Result<OrderStatus, Error>) {
+        let status = match status {
+            Ok(status) => status,
+            Err(_) => return,
+        };
+        if let Ok(order_id) = status.order_id.parse::<u64>() {
+            if let Some(spend) = self.spending.borrow_mut().orders.get_mut(&order_id) {
+                spend.usd = committed(status);
+            }
+        }
+    }
+}
+
+impl Exchange for Capped<'_> {
+    fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error> {
+        self.client.symbol_detail(symbol)
+    }
+
+    fn ticker(&self, symbol: &str) -> Result<Ticker, Error> {
+        self.client.ticker(symbol)
+    }
+
+    fn candles(&self, symbol: &str, frame: &str) -> Result<Vec<Candle>, Error> {
+        self.client.candles(symbol, frame)
+    }
+
+    fn new_order(
+        &self,
+        symbol: &str,
+        amount: Decimal,
+        price: Decimal,
+        side: &str,
+        type_: &str,
+        client_order_id: &str,
+        options: &[&str],
+    ) -> Result<OrderStatus, Error> {
+        if side != Side::Buy.as_str() {
+            return self.client.new_order(
+                symbol,
+                amount,
+                price,
+                side,
+                type_,
+                client_order_id,
+                options,
+            );
+        }
+        let usd = get_usd(amount, price);
+        if let Some((name, cap, spent)) = self.broken(usd) {
+            error!(
+                "{{{}}}: refusing {} order {} ${:.2}, over the per {} cap of ${:.2} with ${:.2} already against it",
+                symbol, side, client_order_id, usd, name, cap, spent
+            );
+            return Err(Error::Rejected(format!(
+                "SpendingCap per {} ${:.2}",
+                name, cap
+            )));
+        }
+
+        let status =
+            self.client
+                .new_order(symbol, amount, price, side, type_, client_order_id, options);
+        if let Ok(status) = &status {
+            if let Ok(order_id) = status.order_id.parse() {
+                let spend = Spend {
+                    time: self.now,
+                    usd: committed(status),
+                };
+                self.spending.borrow_mut().orders.insert(order_id, spend);
+                self.placed.borrow_mut().push(order_id);
+            }
+        }
+        status
+    }
+
+    fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let status = self.client.order_status(order_id);
+        self.update(&status);
+        status
+    }
+
+    fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let status = self.client.cancel_order(order_id);
+        self.update(&status);
+        status
+    }
+
+    fn active_orders(&self) -> Result<Vec<OrderStatus>, Error> {
+        self.client.active_orders()
+    }
+
+    fn balances(&self) -> Result<Vec<Balance>, Error> {
+        self.client.balances()
+    }
+}
+
+/// The synthetic orders of a dry run, by order id. Kept in the strat's state
+/// so they carry on across restarts like real ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DryOrders {
+    orders: HashMap<u64, OrderStatus>,
+    next_id: u64,
+}
+
+impl Default for DryOrders {
+    fn default() -> DryOrders {
+        DryOrders {
+            orders: HashMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl DryOrders {
+    /// Forget the orders other than `order_ids`, which the strat won't ask
+    /// about again.
+    pub fn retain(&mut self, order_ids: &[u64]) {
+        self.orders.retain(|id, _| order_ids.contains(id));
+    }
+}
+
+// fill the whole of an order at `price`
+fn fill(status: &mut OrderStatus, price: Decimal) {
+    status.executed_amount = status.original_amount;
+    status.remaining_amount = Decimal::ZERO;
+    status.avg_execution_price = price;
+    status.is_live = false;
+}
+
+/// An exchange that answers orders and cancels with synthetic statuses
+/// instead of sending them, so a strat can run against real market data
+/// without touching funds. Taker orders fill at the ticker if they would
+/// cross, maker orders rest until the ticker crosses their price. One is
+/// made for each execution.
+pub struct DryRun<'a> {
+    client: &'a dyn Exchange,
+    dry: &'a RefCell<DryOrders>,
+}
+
+impl<'a> DryRun<'a> {
+    pub fn new(client: &'a dyn Exchange, dry: &'a RefCell<DryOrders>) -> DryRun<'a> {
+        DryRun { client, dry }
+    }
+}
+
+impl Exchange for DryRun<'_> {
+    fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error> {
+        self.client.symbol_detail(symbol)
+    }
+
+    fn ticker(&self, symbol: &str) -> Result<Ticker, Error> {
+        self.client.ticker(symbol)
+    }
+
+    fn candles(&self, symbol: &str, frame: &str) -> Result<Vec<Candle>, Error> {
+        self.client.candles(symbol, frame)
+    }
+
+    fn new_order(
+        &self,
+        symbol: &str,
+        amount: Decimal,
+        price: Decimal,
+        side: &str,
+        type_: &str,
+        client_order_id: &str,
+        options: &[&str],
+    ) -> Result<OrderStatus, Error> {
+        let tk = self.client.ticker(symbol)?;
+        let mut dry = self.dry.borrow_mut();
+        let order_id = dry.next_id;
+        dry.next_id += 1;
+
+        let mut status = OrderStatus {
+            order_id: order_id.to_string(),
+            client_order_id: Some(client_order_id.to_owned()),
+            symbol: symbol.to_owned(),
+            side: side.to_owned(),
+            price,
+            is_live: true,
+            remaining_amount: amount,
+            original_amount: amount,
+            ..Default::default()
+        };
+        // the price it would trade at straight away, if any
+        let crossing = match Side::from_str(side) {
+            Ok(Side::Buy) if price >= tk.ask => Some(tk.ask),
+            Ok(Side::Sell) if price <= tk.bid => Some(tk.bid),
+            _ => None,
+        };
+        match (options.contains(&"maker-or-cancel"), crossing) {
+            (true, Some(_)) => {
+                status.is_live = false;
+                status.is_cancelled = true;
+                status.reason = Some("MakerOrCancelWouldTake".to_owned());
+            }
+            (false, Some(crossing)) => fill(&mut status, crossing),
+            (false, None) if options.contains(&"immediate-or-cancel") => {
+                status.is_live = false;
+                status.is_cancelled = true;
+                status.reason = Some("ImmediateOrCancelWouldPost".to_owned());
+            }
+            _ => {}
+        }
+        info!(
+            "{{{}}}: dry run {} {} {} order {} {} @ ${} (bid ${} ask ${}), options {:?}: {}",
+            symbol,
+            type_,
+            side,
+            client_order_id,
+            order_id,
+            amount,
+            price,
+            tk.bid,
+            tk.ask,
+            options,
+            match (status.is_live, status.is_cancelled) {
+                (true, _) => "resting".to_owned(),
+                (_, true) => format!("cancelled ({})", status.reason.clone().unwrap_or_default()),
+                _ => format!("filled @ ${}", status.avg_execution_price),
+            }
+        );
+        dry.orders.insert(order_id, status.clone());
+        Ok(status)
+    }
+
+    fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        let mut dry = self.dry.borrow_mut();
+        let status = match dry.orders.get_mut(&order_id) {
+            Some(status) => status,
+            None => return Err(Error::Rejected(format!("OrderNotFound {}", order_id))),
+        };
+        if status.is_live {
+            let tk = self.client.ticker(&status.symbol)?;
+            let crossed = match Side::from_str(&status.side) {
+                Ok(Side::Buy) => tk.ask <= status.price,
+                Ok(Side::Sell) => tk.bid >= status.price,
+                Err(_) => false,
+            };
+            if crossed {
+                let price = status.price;
+                fill(status, price);
+                info!(
+                    "{{{}}}: dry run order id={} filled {} @ ${}",
+                    status.symbol, order_id, status.executed_amount, price
+                );
+            }
+        }
+        Ok(status.clone())
+    }
+
+    fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error> {
+        // it may have filled since last checked
+        let mut status = self.order_status(order_id)?;
+        if status.is_live {
+            status.is_live = false;
+            status.is_cancelled = true;
+            self.dry
+                .borrow_mut()
+                .orders
+                .insert(order_id, status.clone());
+        }
+        info!(
+            "{{{}}}: dry run cancel order id={} {} remaining",
+            status.symbol, order_id, status.remaining_amount
+        );
+        Ok(status)
+    }
+
+    fn active_orders(&self) -> Result<Vec<OrderStatus>, Error> {
+        Ok(self
+            .dry
+            .borrow()
+            .orders
+            .values()
+            .filter(|s| s.is_live)
+            .cloned()
+            .collect())
+    }
+
+    fn balances(&self) -> Result<Vec<Balance>, Error> {
+        self.client.balances()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use crate::strats::dca_dips::{ladder, DipStrat, MarketBuy, Weighting};
+    use crate::strats::state::{State, Store};
+    use crate::strats::strat::Execute;
+    use chrono::TimeZone;
+    use chrono_tz::UTC;
+    use tempfile::TempDir;
+
+    fn sim(prices: &[i64]) -> SimExchange {
+        let prices: Vec<Decimal> = prices.iter().map(|p| Decimal::from(*p)).collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, Duration::hours(1), &prices);
+        sim.fund("USD", Decimal::from(10_000));
+        sim.fund("BTC", Decimal::from(10));
+        sim
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    // an order for `usd` at $100, a taker one that fills straight away
+    // unless `maker`
+    fn order(ex: &dyn Exchange, side: &str, usd: i64, maker: bool) -> Result<OrderStatus, Error> {
+        let amount = Decimal::from(usd) / Decimal::ONE_HUNDRED;
+        let option = if maker {
+            "maker-or-cancel"
+        } else {
+            "immediate-or-cancel"
+        };
+        ex.new_order(
+            "btcusd",
+            amount,
+            Decimal::ONE_HUNDRED,
+            side,
+            "exchange limit",
+            "caps:1",
+            &[option],
+        )
+    }
+
+    fn buy(ex: &dyn Exchange, usd: i64) -> Result<OrderStatus, Error> {
+        order(ex, "buy", usd, false)
+    }
+
+    fn refused(result: Result<OrderStatus, Error>, per: &str) -> bool {
+        match result {
+            Err(Error::Rejected(reason)) => {
+                reason.starts_with(&format!("SpendingCap per {} ", per))
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn per_order_cap() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            per_order: Some(Decimal::from(50)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let capped = Capped::new(&sim, &caps, &spending, at("2023-10-04T12:00:00Z"), UTC);
+        assert!(refused(buy(&capped, 60), "order"));
+        assert!(buy(&capped, 50).is_ok());
+        assert!(buy(&capped, 50).is_ok());
+        // sells aren't capped
+        assert!(order(&capped, "sell", 1000, false).is_ok());
+        assert_eq!(sim.fills().len(), 3);
+    }
+
+    #[test]
+    fn per_execution_cap() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            per_execution: Some(Decimal::from(150)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let now = at("2023-10-04T12:00:00Z");
+        let capped = Capped::new(&sim, &caps, &spending, now, UTC);
+        assert!(buy(&capped, 100).is_ok());
+        assert!(refused(buy(&capped, 100), "execution"));
+        assert!(buy(&capped, 50).is_ok());
+
+        // the next execution starts afresh
+        let capped = Capped::new(&sim, &caps, &spending, now, UTC);
+        assert!(buy(&capped, 100).is_ok());
+    }
+
+    #[test]
+    fn per_week_cap_resets_on_monday() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            per_week: Some(Decimal::from(150)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let capped = |now| Capped::new(&sim, &caps, &spending, at(now), UTC);
+
+        // wednesday and friday of the same week
+        assert!(buy(&capped("2023-10-04T12:00:00Z"), 100).is_ok());
+        assert!(refused(buy(&capped("2023-10-06T12:00:00Z"), 100), "week"));
+        assert!(buy(&capped("2023-10-08T23:59:59Z"), 50).is_ok());
+        assert!(refused(buy(&capped("2023-10-08T23:59:59Z"), 1), "week"));
+        // the next monday
+        assert!(buy(&capped("2023-10-09T00:00:00Z"), 150).is_ok());
+    }
+
+    #[test]
+    fn per_month_cap_resets_on_the_first() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            per_month: Some(Decimal::from(150)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let capped = |now| Capped::new(&sim, &caps, &spending, at(now), UTC);
+
+        assert!(buy(&capped("2023-10-02T12:00:00Z"), 100).is_ok());
+        assert!(refused(buy(&capped("2023-10-31T12:00:00Z"), 100), "month"));
+        assert!(buy(&capped("2023-11-01T00:00:00Z"), 150).is_ok());
+        assert!(refused(buy(&capped("2023-11-20T00:00:00Z"), 1), "month"));
+    }
+
+    #[test]
+    fn cap_periods_start_in_the_strats_timezone() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            per_month: Some(Decimal::from(150)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let tz = chrono_tz::America::New_York;
+        assert!(buy(
+            &Capped::new(&sim, &caps, &spending, at("2023-10-31T12:00:00Z"), tz),
+            100
+        )
+        .is_ok());
+        // still october in new york
+        let capped = Capped::new(&sim, &caps, &spending, at("2023-11-01T02:00:00Z"), tz);
+        assert!(refused(buy(&capped, 100), "month"));
+        let capped = Capped::new(&sim, &caps, &spending, at("2023-11-01T05:00:00Z"), tz);
+        assert!(buy(&capped, 100).is_ok());
+    }
+
+    #[test]
+    fn lifetime_cap_holds_across_a_restart() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            lifetime: Some(Decimal::from(150)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let capped = Capped::new(&sim, &caps, &spending, at("2023-01-10T12:00:00Z"), UTC);
+        assert!(buy(&capped, 100).is_ok());
+
+        let dir = TempDir::new().unwrap();
+        let mut store = Store::new(dir.path().to_str().unwrap(), "static_btcusd");
+        assert!(store.save(&State {
+            spending: spending.into_inner(),
+            ..Default::default()
+        }));
+        let spending = RefCell::new(store.load().unwrap().spending);
+
+        // long after the order is folded out of the week and month
+        let capped = Capped::new(&sim, &caps, &spending, at("2024-06-10T12:00:00Z"), UTC);
+        assert!(refused(buy(&capped, 100), "lifetime"));
+        assert!(buy(&capped, 50).is_ok());
+        assert!(refused(buy(&capped, 1), "lifetime"));
+    }
+
+    #[test]
+    fn cancelled_orders_free_their_usd() {
+        let sim = sim(&[100]);
+        let caps = Caps {
+            per_week: Some(Decimal::from(150)),
+            ..Default::default()
+        };
+        let spending = RefCell::new(Spending::default());
+        let capped = Capped::new(&sim, &caps, &spending, at("2023-10-04T12:00:00Z"), UTC);
+        // a maker order below the ask rests, and counts while it does
+        let amount = Decimal::ONE;
+        let status = capped
+            .new_order(
+                "btcusd",
+                amount,
+                Decimal::from(90),
+                "buy",
+                "exchange limit",
+                "caps:1",
+                &["maker-or-cancel"],
+            )
+            .unwrap();
+        assert!(status.is_live);
+        assert!(refused(buy(&capped, 100), "week"));
+        capped
+            .cancel_order(status.order_id.parse().unwrap())
+            .unwrap();
+        assert!(buy(&capped, 100).is_ok());
+    }
+
+    #[test]
+    fn dry_run_never_reaches_the_exchange() {
+        let mut sim = sim(&[100, 90]);
+        let dry = RefCell::new(DryOrders::default());
+        let resting = {
+            let dry_run = DryRun::new(&sim, &dry);
+            // a taker order crossing the ask fills at it
+            let status = buy(&dry_run, 100).unwrap();
+            assert!(!status.is_live && !status.is_cancelled);
+            assert_eq!(status.executed_amount, Decimal::ONE);
+            assert_eq!(status.avg_execution_price, Decimal::ONE_HUNDRED);
+
+            // one that doesn't is cancelled like the exchange would
+            let status = dry_run
+                .new_order(
+                    "btcusd",
+                    Decimal::ONE,
+                    Decimal::from(99),
+                    "buy",
+                    "exchange limit",
+                    "d:1",
+                    &["immediate-or-cancel"],
+                )
+                .unwrap();
+            assert_eq!(status.reason.as_deref(), Some("ImmediateOrCancelWouldPost"));
+            let status = order(&dry_run, "buy", 100, true).unwrap();
+            assert_eq!(status.reason.as_deref(), Some("MakerOrCancelWouldTake"));
+
+            // a maker order below the ask rests
+            let status = dry_run
+                .new_order(
+                    "btcusd",
+                    Decimal::ONE,
+                    Decimal::from(95),
+                    "buy",
+                    "exchange limit",
+                    "d:2",
+                    &["maker-or-cancel"],
+                )
+                .unwrap();
+            assert!(status.is_live);
+            assert_eq!(dry_run.active_orders().unwrap().len(), 1);
+            status.order_id.parse::<u64>().unwrap()
+        };
+
+        // it fills once the ticker crosses it
+        sim.advance();
+        let dry_run = DryRun::new(&sim, &dry);
+        let status = dry_run.order_status(resting).unwrap();
+        assert!(!status.is_live);
+        assert_eq!(status.executed_amount, Decimal::ONE);
+        assert_eq!(status.avg_execution_price, Decimal::from(95));
+        assert!(matches!(dry_run.order_status(99), Err(Error::Rejected(_))));
+
+        assert!(sim.fills().is_empty());
+        assert!(sim.active_orders().unwrap().is_empty());
+        assert_eq!(available(&sim, "USD"), Decimal::from(10_000));
+    }
+
+    fn available(sim: &SimExchange, currency: &str) -> Decimal {
+        let balances = sim.balances().unwrap();
+        balances
+            .iter()
+            .find(|b| b.currency == currency)
+            .unwrap()
+            .available
+    }
+
+    #[test]
+    fn dry_run_statuses_fit_the_dips_bookkeeping() {
+        let mut sim = sim(&[100, 94]);
+        let rungs = ladder(Decimal::from(5), Decimal::from(10), &Weighting::Flat).unwrap();
+        let market = MarketBuy {
+            enabled: true,
+            pct: None,
+            retry: None,
+        };
+        let mut strat = DipStrat::new(Decimal::from(300), rungs, market);
+        let dry = RefCell::new(DryOrders::default());
+        let mut orders = vec![];
+        let execute = |sim: &SimExchange, strat: &mut DipStrat, orders: &mut Vec<u64>| {
+            let dry_run = DryRun::new(sim, &dry);
+            let dt = sim.symbol_detail("btcusd").unwrap();
+            let tk = sim.ticker("btcusd").unwrap();
+            strat
+                .execute(&dry_run, "dips_btcusd", "btcusd", orders, &dt, &tk)
+                .unwrap();
+        };
+
+        execute(&sim, &mut strat, &mut orders);
+        assert_eq!(orders, vec![2, 3]);
+        sim.advance();
+        // the 5% rung filled, the 10% one is cancelled and laid again
+        execute(&sim, &mut strat, &mut orders);
+        assert_eq!(orders, vec![5, 6]);
+
+        let filled = dry.borrow().orders[&2].clone();
+        assert_eq!(filled.executed_amount, filled.original_amount);
+        assert!(dry.borrow().orders[&3].is_cancelled);
+        let position = strat.save_state()["position"].clone();
+        assert!(
+            position["amount"]
+                .as_str()
+                .unwrap()
+                .parse::<Decimal>()
+                .unwrap()
+                > Decimal::ZERO
+        );
+
+        assert!(sim.fills().is_empty());
+        assert!(sim.active_orders().unwrap().is_empty());
+    }
+
+    // loses the answer to the `lose`th order placed, after the exchange got
+    // it if `placed`
+    struct Lost<'a> {
+        client: &'a dyn Exchange,
+        lose: u32,
+        placed: bool,
+        calls: std::cell::Cell<u32>,
+    }
+
+    impl Exchange for Lost<'_> {
+        fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error> {
+            self.client.symbol_detail(symbol)
+        }
+
+        fn ticker(&self, symbol: &str) -> Result<Ticker, Error> {
+            self.client.ticker(symbol)
+        }
+
+        fn candles(&self, symbol: &str, frame: &str) -> Result<Vec<Candle>, Error> {
+            self.client.candles(symbol, frame)
+        }
+
+        fn new_order(
+            &self,
+            symbol: &str,
+            amount: Decimal,
+            price: Decimal,
+            side: &str,
+            type_: &str,
+            client_order_id: &str,
+            options: &[&str],
+        ) -> Result<OrderStatus, Error> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() != self.lose {
+                return self.client.new_order(
+                    symbol,
+                    amount,
+                    price,
+                    side,
+                    type_,
+                    client_order_id,
+                    options,
+                );
+            }
+            if self.placed {
+                let _ = self.client.new_order(
+                    symbol,
+                    amount,
+                    price,
+                    side,
+                    type_,
+                    client_order_id,
+                    options,
+                );
+            }
+            Err(Error::Transport("timed out".to_owned()))
+        }
+
+        fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error> {
+            self.client.order_status(order_id)
+        }
+
+        fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error> {
+            self.client.cancel_order(order_id)
+        }
+
+        fn active_orders(&self) -> Result<Vec<OrderStatus>, Error> {
+            self.client.active_orders()
+        }
+
+        fn balances(&self) -> Result<Vec<Balance>, Error> {
+            self.client.balances()
+        }
+    }
+
+    // lays the dips ladder with the answer to its first rung lost, returns
+    // the orders tracked and the usd deferred
+    fn lose_first_rung(placed: bool) -> (Vec<u64>, Decimal) {
+        let sim = sim(&[100]);
+        let lost = Lost {
+            client: &sim,
+            lose: 2,
+            placed,
+            calls: Default::default(),
+        };
+        let rungs = ladder(Decimal::from(5), Decimal::from(10), &Weighting::Flat).unwrap();
+        let market = MarketBuy {
+            enabled: true,
+            pct: None,
+            retry: None,
+        };
+        let mut strat = DipStrat::new(Decimal::from(300), rungs, market);
+        let dt = sim.symbol_detail("btcusd").unwrap();
+        let tk = sim.ticker("btcusd").unwrap();
+        let mut orders = vec![];
+        strat
+            .execute(&lost, "dips_btcusd", "btcusd", &mut orders, &dt, &tk)
+            .unwrap();
+        let deferred = strat.save_state()["deferred"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        (orders, deferred)
+    }
+
+    #[test]
+    fn lost_order_on_the_book_is_tracked() {
+        let (orders, deferred) = lose_first_rung(true);
+        assert_eq!(orders, vec![2, 3]);
+        assert_eq!(deferred, Decimal::ZERO);
+    }
+
+    #[test]
+    fn lost_order_never_placed_is_deferred() {
+        let (orders, deferred) = lose_first_rung(false);
+        assert_eq!(orders, vec![2]);
+        assert_eq!(deferred.round_dp(2), Decimal::from(150));
+    }
+
+    #[test]
+    fn lost_order_is_not_mistaken_for_a_known_one() {
+        let sim = sim(&[100]);
+        // a maker order below the ask rests
+        let known = sim
+            .new_order(
+                "btcusd",
+                Decimal::ONE,
+                Decimal::from(90),
+                "buy",
+                "exchange limit",
+                "caps:1",
+                &["maker-or-cancel"],
+            )
+            .unwrap();
+        let known = known.order_id.parse().unwrap();
+        let result = Err(Error::Transport("timed out".to_owned()));
+        assert!(matches!(
+            placed_maker(&sim, "btcusd", "caps:1", &[known], result),
+            Placed::Refused
+        ));
+        let result = Err(Error::Transport("timed out".to_owned()));
+        assert!(matches!(
+            placed_maker(&sim, "btcusd", "caps:1", &[], result),
+            Placed::Order(_)
+        ));
+    }
+
+    #[test]
+    fn lost_taker_order_is_not_placed_again() {
+        let result = Err(Error::Transport("timed out".to_owned()));
+        assert!(matches!(
+            placed_taker("btcusd", "caps:1", result),
+            Placed::Unknown
+        ));
+        let result = Err(Error::Rejected("InsufficientFunds".to_owned()));
+        assert!(matches!(
+            placed_taker("btcusd", "caps:1", result),
+            Placed::Refused
+        ));
+    }
+}
@@ -0,0 +1,463 @@
+use log::info;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cfg::StratRebalance;
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
+
+use crate::strats::order_util;
+use crate::strats::state::strat_state;
+use crate::strats::strat;
+use crate::strats::strat_util;
+use order_util::{
+    below_min_order_size, client_order_id, new_taker_order, placed_taker, Placed, Side,
+};
+use strat::Execute;
+use strat_util::*;
+
+/// One symbol of the portfolio and its weight in it.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub symbol: String,
+    pub weight: Decimal,
+}
+
+/// Keeps the value of the coins held for each of the `targets` in
+/// proportion to their weights. Each window's usd goes to the symbols below
+/// their target, and with `sell_drift` set a symbol more than that fraction
+/// of the portfolio above its target is sold down to it.
+pub struct RebalanceStrat {
+    pub usd_per_window: Decimal,
+    targets: Vec<Target>,
+    sell_drift: Option<Decimal>,
+    // usd not spent, carried into the next window
+    deferred: Decimal,
+    // cap on the usd of the next execution when funds are short
+    limit: Option<Decimal>,
+}
+
+impl RebalanceStrat {
+    pub fn new(
+        usd_per_window: Decimal,
+        targets: Vec<Target>,
+        sell_drift: Option<Decimal>,
+    ) -> RebalanceStrat {
+        RebalanceStrat {
+            usd_per_window,
+            targets,
+            sell_drift,
+            deferred: Decimal::ZERO,
+            limit: None,
+        }
+    }
+
+    /// The strategy described by a `[[strat_rebalance]]` table, whose symbol
+    /// is the quote currency of every target.
+    pub fn from_cfg(
+        usd_per_window: Decimal,
+        cfg: &StratRebalance,
+    ) -> Result<RebalanceStrat, String> {
+        let quote = cfg.common.symbol.to_lowercase();
+        if cfg.targets.is_empty() {
+            return Err("rebalance needs at least one target".to_owned());
+        }
+        for target in &cfg.targets {
+            let symbol = target.symbol.to_lowercase();
+            if symbol.len() <= quote.len() || !symbol.ends_with(&quote) {
+                return Err(format!(
+                    "rebalance target {} is not quoted in {}",
+                    target.symbol, quote
+                ));
+            }
+            if target.weight < Decimal::ZERO {
+                return Err("rebalance target weights can't be negative".to_owned());
+            }
+        }
+        if cfg
+            .targets
+            .iter()
+            .map(|t| t.weight)
+            .sum::<Decimal>()
+            .is_zero()
+        {
+            return Err("rebalance target weights sum to zero".to_owned());
+        }
+        if let Some(pct) = cfg.sell_drift_pct {
+            if pct <= Decimal::ZERO || pct > Decimal::ONE_HUNDRED {
+                return Err("sell_drift_pct needs 0 < pct <= 100".to_owned());
+            }
+        }
+        let targets = cfg
+            .targets
+            .iter()
+            .map(|t| Target {
+                symbol: t.symbol.to_lowercase(),
+                weight: t.weight,
+            })
+            .collect();
+        Ok(RebalanceStrat::new(
+            usd_per_window,
+            targets,
+            cfg.sell_drift_pct.map(|pct| pct / Decimal::ONE_HUNDRED),
+        ))
+    }
+}
+
+// usd the last window left unspent
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct RebalanceState {
+    deferred: Decimal,
+}
+
+impl Execute for RebalanceStrat {
+    fn execute(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        _dt: &SymbolDetail,
+        _tk: &Ticker,
+    ) -> Result<(), Error> {
+        let limit = self.limit.take();
+        let mut infos = vec![];
+        for target in &self.targets {
+            infos.push(get_info(client, &target.symbol)?);
+        }
+        let balances = client.balances()?;
+        let balance = |currency: &str| {
+            balances
+                .iter()
+                .find(|b| b.currency.eq_ignore_ascii_case(currency))
+                .cloned()
+                .unwrap_or_default()
+        };
+        // coins resting in orders still count towards the weights
+        let held = |currency: &str| balance(currency).amount;
+
+        // what each target is worth at the last trade, and how far short of
+        // its weight of the portfolio plus the new usd it is
+        let values: Vec<Decimal> = infos
+            .iter()
+            .map(|(dt, tk)| held(&dt.base_currency) * tk.last)
+            .collect();
+        let mut usd = self.usd_per_window + self.deferred;
+        if let Some(limit) = limit {
+            usd = usd.min(limit);
+        }
+        let total = values.iter().sum::<Decimal>() + usd;
+        let total_weight: Decimal = self.targets.iter().map(|t| t.weight).sum();
+        let mut gaps = vec![];
+        for (target, value) in self.targets.iter().zip(&values) {
+            let target_value = total * target.weight / total_weight;
+            info!(
+                "{{{}}}: {} holds ${:.2} of target ${:.2}",
+                symbol, target.symbol, value, target_value
+            );
+            gaps.push(target_value - value);
+        }
+        orders.clear();
+
+        // sell down the targets that have drifted too far above their weight
+        let mut proceeds = Decimal::ZERO;
+        if let Some(drift) = self.sell_drift {
+            for (i, target) in self.targets.iter().enumerate() {
+                let over = -gaps[i];
+                if over <= total * drift {
+                    continue;
+                }
+                let (dt, tk) = &infos[i];
+                // but only the coins not held by orders can be sold
+                let amount = get_amount(tk.bid, over).min(balance(&dt.base_currency).available);
+                if below_min_order_size(dt, amount) {
+                    continue;
+                }
+                info!(
+                    "{{{}}}: {} is ${:.2} over target, selling",
+                    symbol, target.symbol, over
+                );
+                let client_order_id = client_order_id(id, &format!("sell_{}", target.symbol));
+                let status = new_taker_order(
+                    client,
+                    dt,
+                    &client_order_id,
+                    &target.symbol,
+                    Side::Sell,
+                    amount,
+                    tk.bid,
+                );
+                // a refused order sold nothing, and what one that may have
+                // gone through sold isn't spent
+                if let Placed::Order(status) = placed_taker(symbol, &client_order_id, status) {
+                    proceeds += get_usd(status.executed_amount, status.avg_execution_price);
+                    if let Ok(order_id) = status.order_id.parse() {
+                        orders.push(order_id);
+                    }
+                }
+            }
+        }
+
+        // spread the usd over the targets below their weight, in proportion
+        // to how far below they are
+        let usd = usd + proceeds;
+        let short: Decimal = gaps.iter().filter(|g| **g > Decimal::ZERO).sum();
+        let mut spent = Decimal::ZERO;
+        for (i, target) in self.targets.iter().enumerate() {
+            if gaps[i] <= Decimal::ZERO {
+                continue;
+            }
+            let (dt, tk) = &infos[i];
+            let buy = usd * gaps[i] / short;
+            let amount = get_amount(tk.ask, buy);
+            if below_min_order_size(dt, amount) {
+                info!(
+                    "{{{}}}: {} ${:.2} is below the minimum order size {}",
+                    symbol, target.symbol, buy, dt.min_order_size
+                );
+                continue;
+            }
+            let client_order_id = client_order_id(id, &format!("buy_{}", target.symbol));
+            let status = new_taker_order(
+                client,
+                dt,
+                &client_order_id,
+                &target.symbol,
+                Side::Buy,
+                amount,
+                tk.ask,
+            );
+            // a refused order's usd is deferred with the rest of the unspent
+            match placed_taker(symbol, &client_order_id, status) {
+                Placed::Order(status) => {
+                    spent += get_usd(status.executed_amount, status.avg_execution_price);
+                    if let Ok(order_id) = status.order_id.parse() {
+                        orders.push(order_id);
+                    }
+                }
+                Placed::Refused => {}
+                // counted as spent rather than risk buying twice
+                Placed::Unknown => spent += buy,
+            }
+        }
+
+        self.deferred = (usd - spent).max(Decimal::ZERO);
+        if self.deferred.round_dp(2) > Decimal::ZERO {
+            info!(
+                "{{{}}}: deferring ${:.2} unspent to the next window",
+                symbol, self.deferred
+            );
+        }
+        Ok(())
+    }
+
+    fn symbols(&self) -> Option<Vec<String>> {
+        Some(self.targets.iter().map(|t| t.symbol.clone()).collect())
+    }
+
+    fn usd_needed(&self, _tk: &Ticker, _follow_up: bool) -> Decimal {
+        self.usd_per_window + self.deferred
+    }
+
+    fn limit_usd(&mut self, usd: Decimal) {
+        self.limit = Some(usd);
+    }
+
+    fn carry_missed(&mut self, missed: u32) {
+        self.deferred += self.usd_per_window * Decimal::from(missed);
+    }
+
+    fn save_state(&self) -> Value {
+        json!(RebalanceState {
+            deferred: self.deferred,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: RebalanceState = strat_state(state);
+        self.deferred = state.deferred;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::{Balance, Candle, OrderStatus};
+    use std::cell::RefCell;
+
+    // an exchange of several symbols whose orders fill whole at their price
+    struct Book {
+        prices: Vec<(&'static str, Decimal)>,
+        balances: Vec<Balance>,
+        // symbol, side and amount of each order
+        orders: RefCell<Vec<(String, String, Decimal)>>,
+    }
+
+    impl Book {
+        fn new(prices: &[(&'static str, i64)], balances: &[(&str, i64, i64)]) -> Book {
+            Book {
+                prices: prices
+                    .iter()
+                    .map(|(s, p)| (*s, Decimal::from(*p)))
+                    .collect(),
+                balances: balances
+                    .iter()
+                    .map(|(currency, amount, available)| Balance {
+                        currency: currency.to_string(),
+                        amount: Decimal::from(*amount),
+                        available: Decimal::from(*available),
+                    })
+                    .collect(),
+                orders: RefCell::new(vec![]),
+            }
+        }
+
+        fn price(&self, symbol: &str) -> Result<Decimal, Error> {
+            self.prices
+                .iter()
+                .find(|(s, _)| *s == symbol)
+                .map(|(_, p)| *p)
+                .ok_or_else(|| Error::Rejected(format!("InvalidSymbol {}", symbol)))
+        }
+    }
+
+    impl Exchange for Book {
+        fn symbol_detail(&self, symbol: &str) -> Result<SymbolDetail, Error> {
+            self.price(symbol)?;
+            Ok(SymbolDetail {
+                base_currency: symbol[..symbol.len() - 3].to_uppercase(),
+                quote_currency: "USD".to_owned(),
+                tick_size: Decimal::new(1, 8),
+                quote_increment: Decimal::new(1, 2),
+                min_order_size: Decimal::new(1, 5),
+                status: "open".to_owned(),
+            })
+        }
+
+        fn ticker(&self, symbol: &str) -> Result<Ticker, Error> {
+            let price = self.price(symbol)?;
+            Ok(Ticker {
+                bid: price,
+                ask: price,
+                last: price,
+            })
+        }
+
+        fn candles(&self, _symbol: &str, _frame: &str) -> Result<Vec<Candle>, Error> {
+            Ok(vec![])
+        }
+
+        fn new_order(
+            &self,
+            symbol: &str,
+            amount: Decimal,
+            price: Decimal,
+            side: &str,
+            _type_: &str,
+            client_order_id: &str,
+            _options: &[&str],
+        ) -> Result<OrderStatus, Error> {
+            let mut orders = self.orders.borrow_mut();
+            orders.push((symbol.to_owned(), side.to_owned(), amount));
+            Ok(OrderStatus {
+                order_id: orders.len().to_string(),
+                client_order_id: Some(client_order_id.to_owned()),
+                symbol: symbol.to_owned(),
+                side: side.to_owned(),
+                price,
+                avg_execution_price: price,
+                executed_amount: amount,
+                original_amount: amount,
+                ..Default::default()
+            })
+        }
+
+        fn order_status(&self, order_id: u64) -> Result<OrderStatus, Error> {
+            Err(Error::Rejected(format!("OrderNotFound {}", order_id)))
+        }
+
+        fn cancel_order(&self, order_id: u64) -> Result<OrderStatus, Error> {
+            Err(Error::Rejected(format!("OrderNotFound {}", order_id)))
+        }
+
+        fn active_orders(&self) -> Result<Vec<OrderStatus>, Error> {
+            Ok(vec![])
+        }
+
+        fn balances(&self) -> Result<Vec<Balance>, Error> {
+            Ok(self.balances.clone())
+        }
+    }
+
+    fn strat(sell_drift: Option<Decimal>) -> RebalanceStrat {
+        let targets = ["btcusd", "ethusd"]
+            .iter()
+            .map(|symbol| Target {
+                symbol: symbol.to_string(),
+                weight: Decimal::ONE,
+            })
+            .collect();
+        RebalanceStrat::new(Decimal::from(200), targets, sell_drift)
+    }
+
+    fn execute(strat: &mut RebalanceStrat, book: &Book) -> Vec<(String, String, Decimal)> {
+        let mut orders = vec![];
+        strat
+            .execute(
+                book,
+                "rebalance_usd",
+                "usd",
+                &mut orders,
+                &SymbolDetail::default(),
+                &Ticker::default(),
+            )
+            .unwrap();
+        book.orders.borrow().clone()
+    }
+
+    fn order(symbol: &str, side: &str, amount: i64) -> (String, String, Decimal) {
+        (symbol.to_owned(), side.to_owned(), Decimal::from(amount))
+    }
+
+    #[test]
+    fn buys_the_targets_below_their_weight() {
+        // $300 of btc and $100 of eth, $300 each with the new $200
+        let book = Book::new(
+            &[("btcusd", 100), ("ethusd", 10)],
+            &[("BTC", 3, 3), ("ETH", 10, 10)],
+        );
+        let mut strat = strat(None);
+        assert_eq!(execute(&mut strat, &book), vec![order("ethusd", "buy", 20)]);
+        assert_eq!(strat.deferred, Decimal::ZERO);
+    }
+
+    #[test]
+    fn sells_down_a_drifted_target() {
+        // $1000 of btc is $400 over its half of $1200
+        let book = Book::new(
+            &[("btcusd", 100), ("ethusd", 10)],
+            &[("BTC", 10, 10), ("ETH", 0, 0)],
+        );
+        let mut strat = strat(Some(Decimal::new(1, 1)));
+        assert_eq!(
+            execute(&mut strat, &book),
+            vec![order("btcusd", "sell", 4), order("ethusd", "buy", 60)]
+        );
+    }
+
+    #[test]
+    fn sells_only_the_available_coins() {
+        // 8 of the 10 btc are held by orders
+        let book = Book::new(
+            &[("btcusd", 100), ("ethusd", 10)],
+            &[("BTC", 10, 2), ("ETH", 0, 0)],
+        );
+        let mut strat = strat(Some(Decimal::new(1, 1)));
+        assert_eq!(
+            execute(&mut strat, &book),
+            vec![order("btcusd", "sell", 2), order("ethusd", "buy", 40)]
+        );
+    }
+}
@@ -0,0 +1,579 @@
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, Offset,
+    TimeZone, Timelike, Weekday,
+};
+use chrono_tz::Tz;
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+
+// how far ahead to look for a cron match, enough to reach the next Feb 29
+const CRON_HORIZON_DAYS: i64 = 366 * 8;
+
+/// When a strat executes, in the wall-clock time of its timezone.
+///
+/// Parsed from one of:
+/// - `every <n>m` / `every <n>h`, counted from midnight of 1970-01-01
+/// - `every <n>d [at HH:MM[:SS]]`, counted from 1970-01-01
+/// - `every <n>w [on <weekday>] [at HH:MM[:SS]]`, counted from the week of 1970-01-05
+/// - `daily [at HH:MM[:SS]]`
+/// - `weekly on <weekday>[,<weekday>...] [at HH:MM[:SS]]`
+/// - `monthly on day <n> [at HH:MM[:SS]]`, on the last day of shorter months
+/// - a cron expression of `[sec] min hour day-of-month month day-of-week`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Every(Duration),
+    EveryDays(i64, NaiveTime),
+    EveryWeeks(i64, Weekday, NaiveTime),
+    Weekly(Vec<Weekday>, NaiveTime),
+    Monthly(u32, NaiveTime),
+    Cron(Cron),
+}
+
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).expect("1970-01-01 is a date")
+}
+
+/// The start of a day.
+pub fn midnight() -> NaiveTime {
+    NaiveTime::from_hms_opt(0, 0, 0).expect("00:00:00 is a time")
+}
+
+// none past the last year chrono can represent
+fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let (y, m) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    Some(NaiveDate::from_ymd_opt(y, m, 1)?.pred_opt()?.day())
+}
+
+fn weekday_from_number(day: u32) -> Option<Weekday> {
+    // 1 is monday, 7 is sunday
+    let days = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+    days.get((day as usize).checked_sub(1)?).copied()
+}
+
+impl Schedule {
+    /// The legacy `trade_day`/`trade_hr`/`trade_min`/`trade_sec` weekly slot.
+    pub fn weekly(day: u32, hr: u32, min: u32, sec: u32) -> Result<Schedule, String> {
+        let weekday = weekday_from_number(day).ok_or(format!("invalid trade_day: {}", day))?;
+        let at = NaiveTime::from_hms_opt(hr, min, sec)
+            .ok_or(format!("invalid trade time: {}:{}:{}", hr, min, sec))?;
+        Ok(Schedule::Weekly(vec![weekday], at))
+    }
+
+    /// How many times the schedule fired after `from` up to and including `to`,
+    /// counting no higher than `limit`.
+    pub fn count_between(&self, from: NaiveDateTime, to: NaiveDateTime, limit: u32) -> u32 {
+        let mut count = 0;
+        let mut t = from;
+        while count < limit {
+            match self.next_after(t) {
+                Some(next) if next <= to => {
+                    count += 1;
+                    t = next;
+                }
+                _ => break,
+            }
+        }
+        count
+    }
+
+    /// The first time strictly after `now`.
+    pub fn next_after(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Schedule::Every(period) => {
+                let period = period.num_seconds();
+                let elapsed = (now - epoch().and_time(midnight())).num_seconds();
+                let n = elapsed.div_euclid(period) + 1;
+                Some(epoch().and_time(midnight()) + Duration::seconds(n * period))
+            }
+            Schedule::EveryDays(days, at) => (0..=*days)
+                .map(|i| now.date() + Duration::days(i))
+                .filter(|d| (*d - epoch()).num_days().rem_euclid(*days) == 0)
+                .map(|d| d.and_time(*at))
+                .find(|t| *t > now),
+            Schedule::EveryWeeks(weeks, on, at) => {
+                let monday = epoch() + Duration::days(4);
+                (0..=7 * (weeks + 1))
+                    .map(|i| now.date() + Duration::days(i))
+                    .filter(|d| d.weekday() == *on)
+                    .filter(|d| ((*d - monday).num_days().div_euclid(7)).rem_euclid(*weeks) == 0)
+                    .map(|d| d.and_time(*at))
+                    .find(|t| *t > now)
+            }
+            Schedule::Weekly(on, at) => (0..=7)
+                .map(|i| now.date() + Duration::days(i))
+                .filter(|d| on.contains(&d.weekday()))
+                .map(|d| d.and_time(*at))
+                .find(|t| *t > now),
+            Schedule::Monthly(day, at) => {
+                let (mut year, mut month) = (now.year(), now.month());
+                loop {
+                    let d = (*day).min(days_in_month(year, month)?);
+                    let t = NaiveDate::from_ymd_opt(year, month, d)?.and_time(*at);
+                    if t > now {
+                        return Some(t);
+                    }
+                    if month == 12 {
+                        year += 1;
+                        month = 1;
+                    } else {
+                        month += 1;
+                    }
+                }
+            }
+            Schedule::Cron(cron) => cron.next_after(now),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Every(period) if period.num_minutes() % 60 == 0 => {
+                write!(f, "every {}h", period.num_hours())
+            }
+            Schedule::Every(period) => write!(f, "every {}m", period.num_minutes()),
+            Schedule::EveryDays(n, at) => write!(f, "every {}d at {}", n, at),
+            Schedule::EveryWeeks(n, on, at) => write!(f, "every {}w on {} at {}", n, on, at),
+            Schedule::Weekly(on, at) => {
+                let on: Vec<String> = on.iter().map(|d| d.to_string()).collect();
+                write!(f, "weekly on {} at {}", on.join(","), at)
+            }
+            Schedule::Monthly(day, at) => write!(f, "monthly on day {} at {}", day, at),
+            Schedule::Cron(cron) => write!(f, "{}", cron.expr),
+        }
+    }
+}
+
+/// The instant a wall-clock time occurs in `tz`.
+///
+/// A time repeated when the clocks go back resolves to its first occurrence.
+/// A time skipped when the clocks go forward is moved later by the length of
+/// the gap, e.g. 02:30 becomes 03:30 when 02:00 jumps to 03:00.
+pub fn resolve_local(tz: Tz, local: NaiveDateTime) -> DateTime<Tz> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(t) => t,
+        LocalResult::Ambiguous(first, _) => first,
+        LocalResult::None => {
+            // the offset in effect before the gap
+            let before = tz.offset_from_utc_datetime(&(local - Duration::days(1)));
+            let utc = local - Duration::seconds(before.fix().local_minus_utc() as i64);
+            tz.from_utc_datetime(&utc)
+        }
+    }
+}
+
+/// A window after each scheduled time to run in, split into `slices`
+/// runs each at a random time within its share of the window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub length: Duration,
+    pub slices: u32,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window {
+            length: Duration::zero(),
+            slices: 1,
+        }
+    }
+}
+
+impl Window {
+    /// A random offset from the scheduled time for the given slice.
+    pub fn offset(&self, slice: u32) -> Duration {
+        let share = self.length.num_seconds() / self.slices as i64;
+        let jitter = if share > 0 {
+            rand::thread_rng().gen_range(0..share)
+        } else {
+            0
+        };
+        Duration::seconds(share * slice as i64 + jitter)
+    }
+}
+
+/// A strat's `window_mins` and `slices`.
+pub fn window_from_cfg(window_mins: Option<u32>, slices: Option<u32>) -> Result<Window, String> {
+    let window = Window {
+        length: Duration::minutes(window_mins.unwrap_or(0) as i64),
+        slices: slices.unwrap_or(1),
+    };
+    if window.slices == 0 {
+        return Err("slices must be at least 1".to_owned());
+    }
+    if window.slices > 1 && window.length.is_zero() {
+        return Err("slices need a window_mins to be spread across".to_owned());
+    }
+    Ok(window)
+}
+
+/// What to do about scheduled runs missed while the process was down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchUp {
+    // wait for the next scheduled run
+    Skip,
+    // run once straight away for all of the missed runs
+    RunOnce,
+    // run once straight away and add the usd of the other missed runs
+    // to the strat, e.g. the dips buckets
+    Carry,
+}
+
+impl FromStr for CatchUp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(CatchUp::Skip),
+            "run_once" => Ok(CatchUp::RunOnce),
+            "carry" => Ok(CatchUp::Carry),
+            _ => Err(format!("unknown catch_up policy: {}", s)),
+        }
+    }
+}
+
+fn parse_at(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|_| format!("invalid time: {}", s))
+}
+
+// split off a trailing "at HH:MM[:SS]", midnight if there is none
+fn split_at<'a>(words: &'a [&'a str]) -> Result<(&'a [&'a str], NaiveTime), String> {
+    match words {
+        [rest @ .., "at", t] => Ok((rest, parse_at(t)?)),
+        _ => Ok((words, midnight())),
+    }
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday, String> {
+    s.parse().map_err(|_| format!("invalid weekday: {}", s))
+}
+
+fn parse_every(n: &str) -> Result<(i64, char), String> {
+    let unit = n.chars().last().ok_or("missing interval")?;
+    let count: i64 = n[..n.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| format!("invalid interval: {}", n))?;
+    if count <= 0 {
+        return Err(format!("invalid interval: {}", n));
+    }
+    Ok((count, unit))
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let words: Vec<&str> = lower.split_whitespace().collect();
+        let (words, at) = split_at(&words)?;
+
+        let schedule = match words {
+            ["every", n] => match parse_every(n)? {
+                (n, 'm') => Schedule::Every(Duration::minutes(n)),
+                (n, 'h') => Schedule::Every(Duration::hours(n)),
+                (n, 'd') => Schedule::EveryDays(n, at),
+                (n, 'w') => Schedule::EveryWeeks(n, Weekday::Mon, at),
+                _ => return Err(format!("invalid interval unit: {}", n)),
+            },
+            ["every", n, "on", day] => match parse_every(n)? {
+                (n, 'w') => Schedule::EveryWeeks(n, parse_weekday(day)?, at),
+                _ => return Err(format!("only weeks can be on a weekday: {}", s)),
+            },
+            ["daily"] => Schedule::EveryDays(1, at),
+            ["weekly", "on", days] => Schedule::Weekly(
+                days.split(',')
+                    .map(parse_weekday)
+                    .collect::<Result<_, _>>()?,
+                at,
+            ),
+            ["monthly", "on", "day", day] => match day.parse() {
+                Ok(day) if (1..=31).contains(&day) => Schedule::Monthly(day, at),
+                _ => return Err(format!("invalid day of month: {}", day)),
+            },
+            _ => Schedule::Cron(s.parse()?),
+        };
+
+        if let Schedule::Every(period) = &schedule {
+            if *period < Duration::minutes(1) {
+                return Err(format!("interval too short: {}", s));
+            }
+        }
+        let start = NaiveDate::from_ymd_opt(2000, 1, 1).map(|d| d.and_time(midnight()));
+        if start.and_then(|t| schedule.next_after(t)).is_none() {
+            return Err(format!("schedule never fires: {}", s));
+        }
+        Ok(schedule)
+    }
+}
+
+/// A strat's `schedule` string, or its weekly trade day and time.
+pub fn schedule_from_cfg(
+    schedule: Option<&str>,
+    day: Option<u32>,
+    hr: Option<u32>,
+    min: Option<u32>,
+    sec: Option<u32>,
+) -> Result<Schedule, String> {
+    match (schedule, day) {
+        (Some(schedule), _) => schedule.parse(),
+        (None, Some(day)) => {
+            Schedule::weekly(day, hr.unwrap_or(0), min.unwrap_or(0), sec.unwrap_or(0))
+        }
+        (None, None) => Err("either schedule or trade_day is required".to_owned()),
+    }
+}
+
+/// A cron expression with an optional leading seconds field.
+/// Day of month and day of week match either one when both are restricted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    secs: Vec<bool>,
+    mins: Vec<bool>,
+    hours: Vec<bool>,
+    doms: Vec<bool>,
+    months: Vec<bool>,
+    dows: Vec<bool>,
+    dom_any: bool,
+    dow_any: bool,
+}
+
+// parse a field of `*`, `n`, `a-b` and `/step`, joined by commas
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut set = vec![false; max as usize + 1];
+    let invalid = || format!("invalid cron field: {}", field);
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((lo, hi)) = range.split_once('-') {
+            (
+                lo.parse().map_err(|_| invalid())?,
+                hi.parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let n = range.parse().map_err(|_| invalid())?;
+            // a single value with a step runs to the end of the range
+            (n, if step > 1 { max } else { n })
+        };
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(invalid());
+        }
+        for v in (lo..=hi).step_by(step as usize) {
+            set[v as usize] = true;
+        }
+    }
+    Ok(set)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let (sec, rest) = match fields.len() {
+            5 => ("0", &fields[..]),
+            6 => (fields[0], &fields[1..]),
+            _ => return Err(format!("invalid schedule: {}", s)),
+        };
+        let mut dows = parse_field(rest[4], 0, 7)?;
+        // 7 is also sunday
+        if dows[7] {
+            dows[0] = true;
+        }
+        dows.truncate(7);
+        Ok(Cron {
+            expr: fields.join(" "),
+            secs: parse_field(sec, 0, 59)?,
+            mins: parse_field(rest[0], 0, 59)?,
+            hours: parse_field(rest[1], 0, 23)?,
+            doms: parse_field(rest[2], 1, 31)?,
+            months: parse_field(rest[3], 1, 12)?,
+            dows,
+            dom_any: rest[2] == "*",
+            dow_any: rest[4] == "*",
+        })
+    }
+}
+
+impl Cron {
+    fn day_matches(&self, d: NaiveDate) -> bool {
+        if !self.months[d.month() as usize] {
+            return false;
+        }
+        let dom = self.doms[d.day() as usize];
+        let dow = self.dows[d.weekday().num_days_from_sunday() as usize];
+        match (self.dom_any, self.dow_any) {
+            (true, true) => true,
+            (true, false) => dow,
+            (false, true) => dom,
+            (false, false) => dom || dow,
+        }
+    }
+
+    // the first time of day matching the fields at or after `from`
+    fn time_from(&self, from: NaiveTime) -> Option<NaiveTime> {
+        for h in from.hour()..24 {
+            if !self.hours[h as usize] {
+                continue;
+            }
+            let m0 = if h == from.hour() { from.minute() } else { 0 };
+            for m in m0..60 {
+                if !self.mins[m as usize] {
+                    continue;
+                }
+                let s0 = if h == from.hour() && m == from.minute() {
+                    from.second()
+                } else {
+                    0
+                };
+                if let Some(s) = (s0..60).find(|s| self.secs[*s as usize]) {
+                    return NaiveTime::from_hms_opt(h, m, s);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn next_after(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = now.with_nanosecond(0)? + Duration::seconds(1);
+        for i in 0..=CRON_HORIZON_DAYS {
+            let d = start.date() + Duration::days(i);
+            if !self.day_matches(d) {
+                continue;
+            }
+            let from = if i == 0 { start.time() } else { midnight() };
+            if let Some(t) = self.time_from(from) {
+                return Some(d.and_time(t));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use chrono_tz::{Europe, US};
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn next(schedule: &str, now: &str) -> NaiveDateTime {
+        let schedule: Schedule = schedule.parse().unwrap();
+        schedule.next_after(at(now)).unwrap()
+    }
+
+    fn utc(tz: Tz, local: &str) -> NaiveDateTime {
+        resolve_local(tz, at(local)).with_timezone(&Utc).naive_utc()
+    }
+
+    #[test]
+    fn monthly_on_day_31_falls_on_the_last_day_of_shorter_months() {
+        let s = "monthly on day 31 at 09:00";
+        assert_eq!(next(s, "2023-02-01 00:00:00"), at("2023-02-28 09:00:00"));
+        assert_eq!(next(s, "2023-03-31 09:00:00"), at("2023-04-30 09:00:00"));
+        assert_eq!(next(s, "2023-12-31 09:00:00"), at("2024-01-31 09:00:00"));
+        assert_eq!(next(s, "2024-02-01 00:00:00"), at("2024-02-29 09:00:00"));
+    }
+
+    #[test]
+    fn monthly_on_day_29_in_february() {
+        let s = "monthly on day 29";
+        assert_eq!(next(s, "2024-02-01 00:00:00"), at("2024-02-29 00:00:00"));
+        assert_eq!(next(s, "2023-02-01 00:00:00"), at("2023-02-28 00:00:00"));
+        assert_eq!(next(s, "2023-02-28 00:00:00"), at("2023-03-29 00:00:00"));
+    }
+
+    #[test]
+    fn every_3d_across_a_year_boundary() {
+        let s = "every 3d at 12:00";
+        assert_eq!(next(s, "2023-12-28 12:00:00"), at("2023-12-31 12:00:00"));
+        assert_eq!(next(s, "2023-12-31 12:00:00"), at("2024-01-03 12:00:00"));
+    }
+
+    #[test]
+    fn every_2w_on_fri_across_a_year_boundary() {
+        let s = "every 2w on fri at 08:30";
+        assert_eq!(next(s, "2023-12-28 00:00:00"), at("2023-12-29 08:30:00"));
+        assert_eq!(next(s, "2023-12-29 08:30:00"), at("2024-01-12 08:30:00"));
+        assert_eq!(next(s, "2024-01-12 08:30:00"), at("2024-01-26 08:30:00"));
+    }
+
+    #[test]
+    fn cron_day_of_month_or_day_of_week() {
+        // the 1st, a sunday, or any friday
+        let s = "0 12 1 * 5";
+        assert_eq!(next(s, "2023-09-30 12:00:00"), at("2023-10-01 12:00:00"));
+        assert_eq!(next(s, "2023-10-01 12:00:00"), at("2023-10-06 12:00:00"));
+        assert_eq!(next(s, "2023-10-27 12:00:00"), at("2023-11-01 12:00:00"));
+
+        // only restricting one of them matches that one alone
+        assert_eq!(
+            next("0 12 * * 5", "2023-09-30 12:00:00"),
+            at("2023-10-06 12:00:00")
+        );
+        assert_eq!(
+            next("0 12 1 * *", "2023-10-01 12:00:00"),
+            at("2023-11-01 12:00:00")
+        );
+    }
+
+    #[test]
+    fn cron_count_between() {
+        let cron: Schedule = "0 12 1 * 5".parse().unwrap();
+        let (from, to) = (at("2023-10-01 00:00:00"), at("2023-10-31 23:59:59"));
+        // the 1st and the fridays 6, 13, 20 and 27
+        assert_eq!(cron.count_between(from, to, 10), 5);
+        assert_eq!(cron.count_between(from, to, 3), 3);
+        assert_eq!(cron.count_between(to, to, 10), 0);
+    }
+
+    #[test]
+    fn resolve_local_spring_forward_gap() {
+        // 02:00 jumps to 03:00, so 02:30 happens at 03:30 EDT
+        assert_eq!(
+            utc(US::Eastern, "2023-03-12 02:30:00"),
+            at("2023-03-12 07:30:00")
+        );
+        // 01:00 jumps to 02:00, so 01:30 happens at 02:30 BST
+        assert_eq!(
+            utc(Europe::London, "2023-03-26 01:30:00"),
+            at("2023-03-26 01:30:00")
+        );
+    }
+
+    #[test]
+    fn resolve_local_fall_back_overlap() {
+        // 01:30 happens twice, first in EDT
+        assert_eq!(
+            utc(US::Eastern, "2023-11-05 01:30:00"),
+            at("2023-11-05 05:30:00")
+        );
+        // and first in BST
+        assert_eq!(
+            utc(Europe::London, "2023-10-29 01:30:00"),
+            at("2023-10-29 00:30:00")
+        );
+    }
+}
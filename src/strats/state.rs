@@ -0,0 +1,270 @@
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::exchange::OrderStatus;
+use crate::strats::order_util::{DryOrders, Spending};
+
+/// Version of the on-disk state format. Bump it when the format changes
+/// and teach `migrate` how to upgrade from the previous version.
+pub const STATE_VERSION: u32 = 1;
+
+/// What a strategy instance checkpoints between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub version: u32,
+    // outstanding order ids
+    pub orders: Vec<u64>,
+    pub last_run: Option<DateTime<Utc>>,
+    // state specific to the Execute implementation
+    pub strat: Value,
+    // usd put into buy orders, counted against the spending caps
+    pub spending: Spending,
+    // the synthetic orders of a dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<DryOrders>,
+}
+
+/// Coins filled by a strat's orders and the usd they came to. Strats that
+/// buy keep theirs under "position" in their state so others, e.g. take
+/// profit, can see what they hold.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub amount: Decimal,
+    pub cost: Decimal,
+}
+
+impl Position {
+    /// Add the filled part of an order.
+    pub fn add_fill(&mut self, status: &OrderStatus) {
+        self.amount += status.executed_amount;
+        self.cost += status.executed_amount * status.avg_execution_price;
+    }
+
+    pub fn avg_cost(&self) -> Option<Decimal> {
+        if self.amount > Decimal::ZERO {
+            Some(self.cost / self.amount)
+        } else {
+            None
+        }
+    }
+}
+
+impl State {
+    /// The position the strat saved in its state, if it keeps one.
+    pub fn position(&self) -> Option<Position> {
+        self.strat
+            .get("position")
+            .and_then(|p| serde_json::from_value(p.clone()).ok())
+    }
+}
+
+/// Read back the state a strat saved with `save_state` as the struct it was
+/// saved from. Fields an older state lacks take their defaults, and a state
+/// that doesn't fit is ignored.
+pub fn strat_state<T: DeserializeOwned + Default>(state: &Value) -> T {
+    if state.is_null() {
+        return T::default();
+    }
+    serde_json::from_value(state.clone()).unwrap_or_else(|e| {
+        warn!("ignoring saved strat state: {}", e);
+        T::default()
+    })
+}
+
+/// A json file per strategy instance in the state directory.
+pub struct Store {
+    id: String,
+    path: PathBuf,
+    readonly: bool,
+}
+
+impl Store {
+    pub fn new(dir: &str, id: &str) -> Store {
+        Store {
+            id: id.to_owned(),
+            path: PathBuf::from(dir).join(format!("{}.json", id)),
+            readonly: false,
+        }
+    }
+
+    pub fn load(&mut self) -> Option<State> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(_) => {
+                info!("{{{}}}: no saved state at {:?}", self.id, self.path);
+                return None;
+            }
+        };
+        let value: Value = match serde_json::from_str(&data) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("{{{}}}: corrupt state {:?}: {}", self.id, self.path, e);
+                // keep the file around for inspection rather than overwriting it
+                self.readonly = true;
+                return None;
+            }
+        };
+        let state = self.migrate(value);
+        debug!("{{{}}}: loaded state {:?}", self.id, state);
+        state
+    }
+
+    fn migrate(&mut self, value: Value) -> Option<State> {
+        let version = value.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+        if version > STATE_VERSION {
+            error!(
+                "{{{}}}: state {:?} is version {} but only {} is understood. Not loading or saving state.",
+                self.id, self.path, version, STATE_VERSION
+            );
+            self.readonly = true;
+            return None;
+        }
+        match serde_json::from_value::<State>(value) {
+            Ok(mut state) => {
+                state.version = STATE_VERSION;
+                Some(state)
+            }
+            Err(e) => {
+                error!("{{{}}}: invalid state {:?}: {}", self.id, self.path, e);
+                self.readonly = true;
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, state: &State) -> bool {
+        if self.readonly {
+            return false;
+        }
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                warn!("{{{}}}: could not create {:?}: {}", self.id, dir, e);
+                return false;
+            }
+        }
+        let data = match serde_json::to_string_pretty(state) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("{{{}}}: could not serialize state: {}", self.id, e);
+                return false;
+            }
+        };
+        // write then rename so a crash never leaves a half written file
+        let tmp = self.path.with_extension("json.tmp");
+        if let Err(e) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, &self.path)) {
+            warn!(
+                "{{{}}}: could not save state {:?}: {}",
+                self.id, self.path, e
+            );
+            return false;
+        }
+        debug!("{{{}}}: saved state {:?}", self.id, state);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    fn store(dir: &TempDir) -> Store {
+        Store::new(dir.path().to_str().unwrap(), "dips_btcusd")
+    }
+
+    fn path(dir: &TempDir) -> PathBuf {
+        dir.path().join("dips_btcusd.json")
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let mut store = store(&dir);
+        assert!(store.load().is_none());
+
+        let state = State {
+            version: STATE_VERSION,
+            orders: vec![3, 5],
+            strat: json!({ "position": { "amount": "1.5", "cost": "150" } }),
+            ..Default::default()
+        };
+        assert!(store.save(&state));
+        // the temporary file was renamed over the state
+        assert!(!dir.path().join("dips_btcusd.json.tmp").exists());
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.version, STATE_VERSION);
+        assert_eq!(loaded.orders, vec![3, 5]);
+        assert_eq!(
+            loaded.position(),
+            Some(Position {
+                amount: Decimal::new(15, 1),
+                cost: Decimal::from(150),
+            })
+        );
+    }
+
+    #[test]
+    fn save_creates_the_dir() {
+        let dir = TempDir::new().unwrap();
+        let nested = dir.path().join("dry_run");
+        let mut store = Store::new(nested.to_str().unwrap(), "dips_btcusd");
+        assert!(store.save(&State::default()));
+        assert!(store.load().is_some());
+    }
+
+    #[test]
+    fn corrupt_state_is_left_alone() {
+        let dir = TempDir::new().unwrap();
+        fs::write(path(&dir), "{ not json").unwrap();
+        let mut store = store(&dir);
+        assert!(store.load().is_none());
+        assert!(!store.save(&State::default()));
+        assert_eq!(fs::read_to_string(path(&dir)).unwrap(), "{ not json");
+    }
+
+    #[test]
+    fn newer_state_is_left_alone() {
+        let dir = TempDir::new().unwrap();
+        let newer = json!({ "version": STATE_VERSION + 1, "orders": [7] }).to_string();
+        fs::write(path(&dir), &newer).unwrap();
+        let mut store = store(&dir);
+        assert!(store.load().is_none());
+        assert!(!store.save(&State::default()));
+        assert_eq!(fs::read_to_string(path(&dir)).unwrap(), newer);
+    }
+
+    #[test]
+    fn older_state_is_migrated() {
+        let dir = TempDir::new().unwrap();
+        fs::write(path(&dir), json!({ "orders": [7] }).to_string()).unwrap();
+        let mut store = store(&dir);
+        let state = store.load().unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.orders, vec![7]);
+        assert!(store.save(&state));
+    }
+
+    #[test]
+    fn strat_state_falls_back_to_the_default() {
+        #[derive(Debug, Default, PartialEq, Deserialize)]
+        #[serde(default)]
+        struct S {
+            deferred: Decimal,
+        }
+        assert_eq!(strat_state::<S>(&Value::Null), S::default());
+        assert_eq!(
+            strat_state::<S>(&json!({ "deferred": "2.5" })).deferred,
+            Decimal::new(25, 1)
+        );
+        assert_eq!(strat_state::<S>(&json!({ "deferred": [] })), S::default());
+    }
+}
@@ -1,90 +1,562 @@
-use log::info;
-use std::sync::{Arc, Condvar, Mutex};
+use chrono::Duration as cDuration;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::cell::RefCell;
 use std::time::Duration as Duration2;
 use std::time::Instant;
 
-use gemini_rust::{GeminiClient, SymbolDetail, Ticker};
+use crate::cfg::{Cfg, Common};
+use crate::error::Error;
+use crate::exchange::{Exchange, SharedExchange, SymbolDetail, Ticker};
+use crate::strats::clock::Clock;
+use crate::strats::funds::{available_balance, InsufficientFunds, SharedReservations};
+use crate::strats::guard::PriceGuard;
+use crate::strats::order_util::{
+    reconcile_orders, Capped, Caps, DryOrders, DryRun, Reconcile, Shutdown, Spending,
+};
+use crate::strats::schedule::{schedule_from_cfg, window_from_cfg, CatchUp, Schedule, Window};
+use crate::strats::state::{State, Store, STATE_VERSION};
 
 use crate::strats::strat_util;
 use strat_util::*;
 
+// the most missed runs caught up on, for schedules that fire often
+const MAX_MISSED_RUNS: u32 = 1000;
+// the retries of a run the exchange keeps refusing before giving up on it
+const MAX_REJECTED_RETRIES: u32 = 3;
+
 pub trait Execute {
+    /// Place the orders of a scheduled run. An error should leave the strat
+    /// as it was, as a transport one has it called again 15s later, and a
+    /// rejected one up to `MAX_REJECTED_RETRIES` times.
     fn execute(
         &mut self,
-        client: &GeminiClient,
+        client: &dyn Exchange,
+        id: &str,
         symbol: &str,
         orders: &mut Vec<u64>,
         dt: &SymbolDetail,
         tk: &Ticker,
-    ) -> bool;
+    ) -> Result<(), Error>;
+
+    /// The symbols traded by a strat that trades more than the one it is
+    /// configured with. It looks up their details and tickers itself and
+    /// `execute` is handed empty ones.
+    fn symbols(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Ask to be woken for a follow up before the next scheduled execution.
+    fn wake_in(&self) -> Option<Duration2> {
+        None
+    }
+
+    /// Checked whenever the clock is poked while sleeping, e.g. by market
+    /// data. Returning true wakes the strat early to `follow_up`.
+    fn triggered(&self) -> bool {
+        false
+    }
+
+    /// Called instead of `execute` when woken at the time asked for by
+    /// `wake_in`, or by `triggered`.
+    fn follow_up(
+        &mut self,
+        _client: &dyn Exchange,
+        _id: &str,
+        _symbol: &str,
+        _orders: &mut Vec<u64>,
+        _dt: &SymbolDetail,
+        _tk: &Ticker,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The usd the next execution, or follow up, means to spend on top of
+    /// what its own live orders hold. Checked against the available balance
+    /// before it runs.
+    fn usd_needed(&self, _tk: &Ticker, _follow_up: bool) -> Decimal {
+        Decimal::ZERO
+    }
+
+    /// Spend no more than `usd` in the next execution or follow up, as the
+    /// balance is short of `usd_needed`.
+    fn limit_usd(&mut self, _usd: Decimal) {}
+
+    /// Usd set aside for later follow ups that the exchange isn't holding
+    /// for an order, kept out of the balance other strats see.
+    fn usd_reserved(&self) -> Decimal {
+        Decimal::ZERO
+    }
+
+    /// Add the usd of `missed` executions that never happened to the next one.
+    fn carry_missed(&mut self, _missed: u32) {}
+
+    /// Strategy specific state to checkpoint after each execution.
+    fn save_state(&self) -> Value {
+        Value::Null
+    }
+
+    /// Restore the state returned by `save_state` on a previous run.
+    fn load_state(&mut self, _state: &Value) {}
 }
 
 pub struct Strat {
-    client_mutex: Arc<Mutex<GeminiClient>>,
+    client_mutex: SharedExchange,
+    id: String,
     symbol: String,
-    cond_: Arc<(Mutex<bool>, Condvar)>,
-    day: i64,
-    hr: u32,
-    min: u32,
-    sec: u32,
+    clock: Box<dyn Clock + Send>,
+    store: Option<Store>,
+    reconcile: Reconcile,
+    shutdown: Shutdown,
+    schedule: Schedule,
+    timezone: Tz,
+    catch_up: CatchUp,
+    insufficient_funds: InsufficientFunds,
+    reservations: Option<SharedReservations>,
+    caps: Caps,
+    guard: PriceGuard,
+    spending: RefCell<Spending>,
+    // the synthetic orders when in a dry run
+    dry_run: Option<RefCell<DryOrders>>,
+    window: Window,
+    // the current scheduled time and the slice of its window
+    slot: Option<DateTime<Utc>>,
+    slice: u32,
 }
 
 impl Strat {
     pub fn new(
-        client_mutex: Arc<Mutex<GeminiClient>>,
+        client_mutex: SharedExchange,
+        id: &str,
         symbol: &str,
-        cond_: Arc<(Mutex<bool>, Condvar)>,
-        day: i64,
-        hr: u32,
-        min: u32,
-        sec: u32,
+        clock: Box<dyn Clock + Send>,
+        schedule: Schedule,
     ) -> Strat {
         Strat {
             client_mutex,
+            id: id.to_owned(),
             symbol: symbol.to_owned(),
-            cond_,
-            day,
-            hr,
-            min,
-            sec,
+            clock,
+            store: None,
+            reconcile: Reconcile::Adopt,
+            shutdown: Shutdown::Cancel,
+            schedule,
+            timezone: Tz::US__Mountain,
+            catch_up: CatchUp::Skip,
+            insufficient_funds: InsufficientFunds::Scale,
+            reservations: None,
+            caps: Caps::default(),
+            guard: PriceGuard::default(),
+            spending: RefCell::new(Spending::default()),
+            dry_run: None,
+            window: Window::default(),
+            slot: None,
+            slice: 0,
+        }
+    }
+
+    /// A strat for a `[[strat_*]]` table of the given kind. Options the table
+    /// doesn't set fall back to the global ones.
+    pub fn from_cfg(
+        cfg: &Cfg,
+        common: &Common,
+        kind: &str,
+        client_mutex: SharedExchange,
+        clock: Box<dyn Clock + Send>,
+    ) -> Result<Strat, String> {
+        let id = common.id(kind);
+        let schedule = schedule_from_cfg(
+            common.schedule.as_deref(),
+            common.trade_day,
+            common.trade_hr,
+            common.trade_min,
+            common.trade_sec,
+        )?;
+        let policy = |own: &Option<String>, global: &Option<String>, default: &str| {
+            own.clone()
+                .or_else(|| global.clone())
+                .unwrap_or_else(|| default.to_owned())
+        };
+
+        let caps = Caps {
+            per_order: common.max_usd_per_order.or(cfg.max_usd_per_order),
+            per_execution: common.max_usd_per_execution.or(cfg.max_usd_per_execution),
+            per_week: common.max_usd_per_week.or(cfg.max_usd_per_week),
+            per_month: common.max_usd_per_month.or(cfg.max_usd_per_month),
+            lifetime: common.max_usd_lifetime.or(cfg.max_usd_lifetime),
+        };
+        let limits = [
+            caps.per_order,
+            caps.per_execution,
+            caps.per_week,
+            caps.per_month,
+            caps.lifetime,
+        ];
+        if limits.iter().flatten().any(|cap| *cap < Decimal::ZERO) {
+            return Err("max_usd_* caps can't be negative".to_owned());
         }
+
+        let mut strat = Strat::new(client_mutex, &id, &common.symbol, clock, schedule);
+        strat.set_caps(caps);
+        strat.set_dry_run(common.dry_run.or(cfg.dry_run).unwrap_or(false));
+        strat.set_guard(PriceGuard::new(
+            common.max_spread_pct.or(cfg.max_spread_pct),
+            common.max_deviation_pct.or(cfg.max_deviation_pct),
+            common
+                .reference_frame
+                .clone()
+                .or_else(|| cfg.reference_frame.clone()),
+            common.reference_period.or(cfg.reference_period),
+            common.pause_mins.or(cfg.pause_mins),
+        )?);
+        strat.set_reconcile(policy(&common.reconcile, &cfg.reconcile, "adopt").parse()?);
+        strat.set_shutdown(policy(&common.shutdown, &cfg.shutdown, "cancel").parse()?);
+        strat.set_catch_up(policy(&common.catch_up, &cfg.catch_up, "skip").parse()?);
+        strat.set_insufficient_funds(
+            policy(&common.insufficient_funds, &cfg.insufficient_funds, "scale").parse()?,
+        );
+        strat.set_timezone(policy(&common.timezone, &cfg.timezone, "US/Mountain").parse()?);
+        strat.set_window(window_from_cfg(common.window_mins, common.slices)?);
+        Ok(strat)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The share of `usd` for each slice of the window.
+    pub fn share(&self, usd: Decimal) -> Decimal {
+        usd / Decimal::from(self.window.slices)
+    }
+
+    /// Keep time with `clock` instead of the one it was made with.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock + Send>) {
+        self.clock = clock;
+    }
+
+    /// Checkpoint state to the store after every execution and
+    /// restore it when run.
+    pub fn set_store(&mut self, store: Store) {
+        self.store = Some(store);
+    }
+
+    /// How to treat live orders left by a previous run.
+    pub fn set_reconcile(&mut self, reconcile: Reconcile) {
+        self.reconcile = reconcile;
+    }
+
+    /// What to do with live orders when terminated.
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// The timezone the schedule is in.
+    pub fn set_timezone(&mut self, timezone: Tz) {
+        self.timezone = timezone;
+    }
+
+    /// What to do about scheduled runs missed since the last run.
+    pub fn set_catch_up(&mut self, catch_up: CatchUp) {
+        self.catch_up = catch_up;
+    }
+
+    /// What to do when the balance is short of what an execution needs.
+    pub fn set_insufficient_funds(&mut self, insufficient_funds: InsufficientFunds) {
+        self.insufficient_funds = insufficient_funds;
+    }
+
+    /// Share the usd reserved for follow ups with the other strats trading
+    /// on the same account.
+    pub fn set_reservations(&mut self, reservations: SharedReservations) {
+        self.reservations = Some(reservations);
+    }
+
+    /// Limits on the usd put into buy orders, which are refused once they
+    /// would break one.
+    pub fn set_caps(&mut self, caps: Caps) {
+        self.caps = caps;
+    }
+
+    /// Run against the exchange's market data but answer orders and cancels
+    /// with synthetic statuses instead of sending them.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = match dry_run {
+            true => Some(RefCell::new(DryOrders::default())),
+            false => None,
+        };
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    /// Check the ticker before trading on it, pausing when it looks wrong.
+    pub fn set_guard(&mut self, guard: PriceGuard) {
+        self.guard = guard;
+    }
+
+    /// Run at random within a window after each scheduled time, split
+    /// into slices. The strat is expected to spend its share per slice.
+    pub fn set_window(&mut self, window: Window) {
+        self.window = window;
     }
 
-    pub fn run<T: Execute>(&self, ctx: &mut T, immediate: Option<bool>) {
+    // the time of the next slice of the current window, or the first
+    // slice of the next scheduled window
+    fn next_run(&mut self) -> DateTime<Utc> {
+        let now = self.clock.now();
+        self.slice += 1;
+        if self.slot.is_none() || self.slice >= self.window.slices {
+            let dur = get_dur_until_next_target_date(now, &self.schedule, self.timezone);
+            self.slot = Some(now + cDuration::from_std(dur).unwrap());
+            self.slice = 0;
+        }
+        self.slot.unwrap() + self.window.offset(self.slice)
+    }
+
+    fn until(&self, time: DateTime<Utc>) -> Duration2 {
+        (time - self.clock.now()).to_std().unwrap_or_default()
+    }
+
+    // scheduled runs between the last run and now
+    fn missed_runs(&self, last_run: DateTime<Utc>) -> u32 {
+        let local = |t: DateTime<Utc>| t.with_timezone(&self.timezone).naive_local();
+        self.schedule
+            .count_between(local(last_run), local(self.clock.now()), MAX_MISSED_RUNS)
+    }
+
+    // when a sleep starting now ends, in the schedule's timezone and UTC
+    fn wake_time(&self, sleep_interval: Duration2) -> String {
+        let wake = self.clock.now() + cDuration::from_std(sleep_interval).unwrap();
+        format!("{} ({})", wake.with_timezone(&self.timezone), wake)
+    }
+
+    // the usd the other strats of the account have reserved
+    fn reserved_by_others(&self) -> Decimal {
+        self.reservations
+            .as_ref()
+            .map_or(Decimal::ZERO, |r| r.lock().unwrap().others(&self.id))
+    }
+
+    fn reserve<T: Execute>(&self, ctx: &T) {
+        if let Some(reservations) = &self.reservations {
+            reservations
+                .lock()
+                .unwrap()
+                .set(&self.id, ctx.usd_reserved());
+        }
+    }
+
+    // check the available balance covers what the execution needs, and
+    // apply the insufficient funds policy if it doesn't. returns whether to
+    // go ahead.
+    fn check_funds<T: Execute>(
+        &self,
+        ctx: &mut T,
+        client: &dyn Exchange,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+        follow_up: bool,
+    ) -> Result<bool, Error> {
+        if self.insufficient_funds == InsufficientFunds::Ignore {
+            return Ok(true);
+        }
+        let needed = ctx.usd_needed(tk, follow_up);
+        if needed <= Decimal::ZERO {
+            return Ok(true);
+        }
+        // a strat of several symbols is configured with the quote currency
+        let currency = match dt.quote_currency.as_str() {
+            "" => self.symbol.as_str(),
+            quote => quote,
+        };
+        let balance = available_balance(client, currency)?;
+        let reserved = self.reserved_by_others();
+        let available = (balance - reserved).max(Decimal::ZERO);
+        if available >= needed {
+            return Ok(true);
+        }
+        // a follow up finishes what an execution started, so it is only
+        // ever scaled down
+        match self.insufficient_funds {
+            InsufficientFunds::Skip if !follow_up => {
+                warn!(
+                    "{{{}}}: ${:.2} available (${:.2} reserved by other strats) of ${:.2} needed, skipping",
+                    self.symbol, available, reserved, needed
+                );
+                Ok(false)
+            }
+            _ => {
+                warn!(
+                    "{{{}}}: ${:.2} available (${:.2} reserved by other strats) of ${:.2} needed, scaling down",
+                    self.symbol, available, reserved, needed
+                );
+                ctx.limit_usd(available);
+                Ok(true)
+            }
+        }
+    }
+
+    // why the prices aren't safe to trade on, if they aren't. a strat of
+    // several symbols has each of their tickers checked.
+    fn check_prices(
+        &self,
+        client: &dyn Exchange,
+        symbols: &Option<Vec<String>>,
+        tk: &Ticker,
+    ) -> Result<(), String> {
+        let symbols = match symbols {
+            Some(symbols) => symbols,
+            None => return self.guard.check(client, &self.symbol, tk),
+        };
+        for symbol in symbols {
+            // the strat gets its own ticker, and retries if it can't
+            if let Ok(tk) = client.ticker(symbol) {
+                self.guard.check(client, symbol, &tk)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save<T: Execute>(&self, ctx: &T, orders: &[u64]) {
+        if let Some(store) = &self.store {
+            store.save(&State {
+                version: STATE_VERSION,
+                orders: orders.to_vec(),
+                last_run: Some(self.clock.now()),
+                strat: ctx.save_state(),
+                spending: self.spending.borrow().clone(),
+                dry_run: self.dry_run.as_ref().map(|dry| {
+                    let mut dry = dry.borrow_mut();
+                    dry.retain(orders);
+                    dry.clone()
+                }),
+            });
+        }
+    }
+
+    pub fn run<T: Execute>(&mut self, ctx: &mut T, immediate: Option<bool>) {
         let retry_interval = Duration2::new(15, 0);
 
-        let mut sleep_interval = if immediate.unwrap_or(false) {
-            Duration2::new(0, 0)
+        info!(
+            "{{{}}}: {} runs {} {}",
+            self.symbol, self.id, self.schedule, self.timezone
+        );
+
+        let mut next_run = if immediate.unwrap_or(false) {
+            self.clock.now()
         } else {
-            get_dur_until_next_target_date(self.day, self.hr, self.min, self.sec)
+            self.next_run()
         };
 
         let mut orders: Vec<u64> = vec![];
+        if let Some(state) = self.store.as_mut().and_then(Store::load) {
+            info!(
+                "{{{}}}: restored state from {:?}, orders: {:?}",
+                self.symbol, state.last_run, state.orders
+            );
+            orders = state.orders;
+            ctx.load_state(&state.strat);
+            self.spending.replace(state.spending);
+            if let (Some(dry), Some(saved)) = (&self.dry_run, state.dry_run) {
+                dry.replace(saved);
+            }
+
+            let missed = state.last_run.map_or(0, |t| self.missed_runs(t));
+            if missed > 0 {
+                match self.catch_up {
+                    CatchUp::Skip => warn!(
+                        "{{{}}}: missed {} scheduled runs, skipping them",
+                        self.symbol, missed
+                    ),
+                    CatchUp::RunOnce => {
+                        warn!(
+                            "{{{}}}: missed {} scheduled runs, running once now",
+                            self.symbol, missed
+                        );
+                        next_run = self.clock.now();
+                    }
+                    CatchUp::Carry => {
+                        warn!(
+                            "{{{}}}: missed {} scheduled runs, running now and carrying the rest",
+                            self.symbol, missed
+                        );
+                        ctx.carry_missed(missed * self.window.slices - 1);
+                        next_run = self.clock.now();
+                    }
+                }
+            }
+        }
+        self.reserve(ctx);
+        let symbols = ctx.symbols();
+        // a dry run's orders were never on the exchange, and the live ones
+        // there aren't its to touch
+        if self.dry_run() {
+            info!(
+                "{{{}}}: dry run, orders are not sent to the exchange",
+                self.symbol
+            );
+        } else {
+            let client = self.client_mutex.lock().unwrap();
+            for symbol in symbols.as_ref().unwrap_or(&vec![self.symbol.clone()]) {
+                reconcile_orders(&*client, &self.id, symbol, &mut orders, self.reconcile);
+            }
+        }
+        let mut sleep_interval = self.until(next_run);
         let mut run = true;
         let mut is_retry = false;
+        let mut is_follow_up = false;
+        // times the current run was rejected
+        let mut rejections = 0;
+        // tripped the price guard, market data doesn't cut the pause short
+        let mut paused = false;
         let mut start = Instant::now();
+        // a window the last run left going goes on where it was
+        if let Some(wake) = ctx.wake_in() {
+            if wake < sleep_interval {
+                sleep_interval = wake;
+                is_follow_up = true;
+            }
+        }
 
         let seconds = sleep_interval.as_secs() % 60;
         let minutes = (sleep_interval.as_secs() / 60) % 60;
         let hours = (sleep_interval.as_secs() / 60) / 60;
         info!(
-            "{{{}}}: Waking up in {}:{}:{}",
-            self.symbol, hours, minutes, seconds
+            "{{{}}}: Waking up in {}:{}:{} at {}",
+            self.symbol,
+            hours,
+            minutes,
+            seconds,
+            self.wake_time(sleep_interval)
         );
 
         while run {
             run = sleep_or_die(
                 &self.client_mutex,
                 &self.symbol,
-                &self.cond_,
+                &*self.clock,
                 &orders,
                 sleep_interval,
+                match self.dry_run {
+                    Some(_) => Shutdown::Leave,
+                    None => self.shutdown,
+                },
+                &|| !paused && ctx.triggered(),
             );
             if !run {
                 info!("{{{}}}: terminated", self.symbol);
                 return;
             }
+            if !is_follow_up && ctx.triggered() {
+                is_follow_up = true;
+            }
 
+            // the clock may need the exchange, ask before locking it
+            let now = self.clock.now();
             if !is_retry {
                 start = Instant::now();
             }
@@ -92,28 +564,114 @@ impl Strat {
 
             let client = self.client_mutex.lock().unwrap();
 
-            let info = get_info(&client, &self.symbol);
-            if info.is_none() {
-                sleep_interval = retry_interval;
+            let info = match symbols {
+                Some(_) => Ok((SymbolDetail::default(), Ticker::default())),
+                None => get_info(&*client, &self.symbol),
+            };
+            let (dt, tk) = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!("{{{}}}: {}. retry in 15s", self.symbol, e);
+                    sleep_interval = retry_interval;
+                    is_retry = true;
+                    continue;
+                }
+            };
+
+            paused = false;
+            if let Err(reason) = self.check_prices(&*client, &symbols, &tk) {
+                warn!(
+                    "{{{}}}: price guard tripped, {}. pausing for {}m",
+                    self.symbol,
+                    reason,
+                    self.guard.pause.as_secs() / 60
+                );
+                sleep_interval = self.guard.pause;
                 is_retry = true;
+                paused = true;
                 continue;
             }
-            let (dt, tk) = info.unwrap();
 
-            if ctx.execute(&client, &self.symbol, &mut orders, &dt, &tk) == false {
-                sleep_interval = retry_interval;
-                is_retry = true;
-                continue;
+            let funded = match self.check_funds(ctx, &*client, &dt, &tk, is_follow_up) {
+                Ok(funded) => funded,
+                Err(e) => {
+                    warn!("{{{}}}: {}. retry in 15s", self.symbol, e);
+                    sleep_interval = retry_interval;
+                    is_retry = true;
+                    continue;
+                }
+            };
+            // every order placed goes through the caps, and in a dry run
+            // stops there
+            let dry_run = self.dry_run.as_ref().map(|dry| DryRun::new(&*client, dry));
+            let exchange: &dyn Exchange = match &dry_run {
+                Some(dry_run) => dry_run,
+                None => &*client,
+            };
+            let capped = Capped::new(exchange, &self.caps, &self.spending, now, self.timezone);
+            let result = if !funded {
+                Ok(())
+            } else if is_follow_up {
+                ctx.follow_up(&capped, &self.id, &self.symbol, &mut orders, &dt, &tk)
+            } else {
+                ctx.execute(&capped, &self.id, &self.symbol, &mut orders, &dt, &tk)
+            };
+            match result {
+                Ok(()) => {}
+                Err(e @ Error::Transport(_)) => {
+                    warn!("{{{}}}: {}. retry in 15s", self.symbol, e);
+                    sleep_interval = retry_interval;
+                    is_retry = true;
+                    continue;
+                }
+                Err(e @ Error::Rejected(_)) if rejections < MAX_REJECTED_RETRIES => {
+                    rejections += 1;
+                    warn!(
+                        "{{{}}}: {}. retry {}/{} in 15s",
+                        self.symbol, e, rejections, MAX_REJECTED_RETRIES
+                    );
+                    sleep_interval = retry_interval;
+                    is_retry = true;
+                    continue;
+                }
+                Err(e @ Error::Rejected(_)) => {
+                    error!("{{{}}}: {}. giving up until the next run", self.symbol, e);
+                }
+                // asking again gets the same answer, the run is as done as
+                // it will get
+                Err(e) => {
+                    error!("{{{}}}: {}. not retrying", self.symbol, e);
+                }
             }
+            rejections = 0;
+            self.reserve(ctx);
+            // the clock may need the exchange too
+            drop(client);
+            self.save(ctx, &orders);
 
             let dur = Instant::now() - start;
-            sleep_interval = get_dur_until_next_target_date(self.day, self.hr, self.min, self.sec);
+            if !is_follow_up {
+                next_run = self.next_run();
+            }
+            sleep_interval = self.until(next_run);
+            is_follow_up = false;
+            if let Some(wake) = ctx.wake_in() {
+                if wake < sleep_interval {
+                    sleep_interval = wake;
+                    is_follow_up = true;
+                }
+            }
             let seconds = sleep_interval.as_secs() % 60;
             let minutes = (sleep_interval.as_secs() / 60) % 60;
             let hours = (sleep_interval.as_secs() / 60) / 60;
             info!(
-                "{{{}}}: Took {:?}. Waking up in {}:{}:{}",
-                self.symbol, dur, hours, minutes, seconds
+                "{{{}}}: Took {:?}. Waking up in {}:{}:{} at {}",
+                self.symbol,
+                dur,
+                hours,
+                minutes,
+                seconds,
+                self.wake_time(sleep_interval)
             );
         }
     }
@@ -1,93 +1,94 @@
-use chrono::Duration as cDuration;
-use chrono::{Datelike, NaiveTime, TimeZone, Utc};
-use chrono_tz::US::Mountain;
-use log::{debug, error, warn};
-use std::sync::{Condvar, Mutex};
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{debug, warn};
+use rust_decimal::Decimal;
+use std::sync::Mutex;
 use std::time::Duration;
 
-use gemini_rust::{GeminiClient, PublicAPI, SymbolDetail, Ticker};
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
+use crate::strats::clock::Clock;
+use crate::strats::schedule::{resolve_local, Schedule};
 
 use crate::strats::order_util;
-use order_util::cancel_orders_if_open;
+use order_util::{cancel_orders_if_open, cancel_orders_if_unfilled, Shutdown};
 
-pub fn get_amount(price: f32, usd: f32) -> f32 {
+pub fn get_amount(price: Decimal, usd: Decimal) -> Decimal {
     usd / price
 }
 
-pub fn get_usd(amount: f32, price: f32) -> f32 {
+pub fn get_usd(amount: Decimal, price: Decimal) -> Decimal {
     price * amount
 }
 
-pub fn get_info(client: &GeminiClient, symbol: &str) -> Option<(SymbolDetail, Ticker)> {
+pub fn get_info(client: &dyn Exchange, symbol: &str) -> Result<(SymbolDetail, Ticker), Error> {
     // get the details of the symbol
-    let dt = client.symbol_detail(symbol);
-    if dt.is_none() {
-        error!("{{{}}}: Error getting details. retry in 15", symbol);
-        return None;
-    }
-    let detail = dt.unwrap();
+    let detail = client.symbol_detail(symbol)?;
     if detail.status != "open" {
-        error!("{{{}}}: is not open: {}", symbol, detail.status);
-        return None;
+        return Err(Error::Rejected(format!("{} is {}", symbol, detail.status)));
     }
     debug!("{{{}}}: detail: {:?}", symbol, detail);
 
     // get the ticker of the symbol
-    let tk = client.ticker(symbol);
-    if tk.is_none() {
-        error!("{{{}}}: Error getting ticker. retry in 15s", symbol);
-        return None;
-    }
-    let ticker = tk.unwrap();
+    let ticker = client.ticker(symbol)?;
     debug!("{{{}}}: ticker: {:?}", symbol, ticker);
 
-    Some((detail, ticker))
+    Ok((detail, ticker))
 }
 
 pub fn sleep_or_die(
-    client_mutex: &Mutex<GeminiClient>,
+    client_mutex: &Mutex<dyn Exchange + Send>,
     symbol: &str,
-    cond_: &(Mutex<bool>, Condvar),
+    clock: &dyn Clock,
     order_ids: &Vec<u64>,
     sleep_interval: Duration,
+    shutdown: Shutdown,
+    until: &dyn Fn() -> bool,
 ) -> bool {
     let mut run = true;
-    let (lock, cvar) = &*cond_;
-
-    let mut die = lock.lock().unwrap();
-    let result = cvar.wait_timeout(die, sleep_interval).unwrap();
-    debug!("woke up");
-    die = result.0;
-    debug!("die= {}", die);
-    if *die == true {
-        warn!("{{{}}}: Terminated! Cancelling all open orders.", symbol);
+    if !clock.sleep_until(sleep_interval, until) {
         let client = client_mutex.lock().unwrap();
-        cancel_orders_if_open(&client, order_ids);
+        match shutdown {
+            Shutdown::Cancel => {
+                warn!("{{{}}}: Terminated! Cancelling all open orders.", symbol);
+                cancel_orders_if_open(&*client, order_ids);
+            }
+            Shutdown::Leave => {
+                warn!("{{{}}}: Terminated! Leaving open orders resting.", symbol);
+            }
+            Shutdown::CancelUnfilled => {
+                warn!("{{{}}}: Terminated! Cancelling unfilled orders.", symbol);
+                cancel_orders_if_unfilled(&*client, order_ids);
+            }
+        }
         run = false;
     }
     run
 }
 
 pub fn get_dur_until_next_target_date(
-    day: i64,
-    hrs: u32,
-    mins: u32,
-    secs: u32,
+    now: DateTime<Utc>,
+    schedule: &Schedule,
+    tz: Tz,
 ) -> std::time::Duration {
-    let utc = Utc::now().naive_utc();
-    let now = Mountain.from_utc_datetime(&utc);
-
-    let now_date = now.date();
-    let current_day = now_date.weekday().number_from_monday() as i64;
-    let sunday_correction =
-        if current_day == day && now.time() < NaiveTime::from_hms(hrs, mins, secs) {
-            0
-        } else {
-            7
-        };
-    let days_to_sunday = cDuration::days((7 + current_day - day) % 7 + sunday_correction);
-    let target_date = (now_date + days_to_sunday).and_hms(hrs, mins, secs);
-    let duration = target_date.signed_duration_since(now).to_std().unwrap();
+    let now = tz.from_utc_datetime(&now.naive_utc());
+
+    // in the hour repeated when the clocks go back, a wall time resolves to
+    // its first occurrence which may already be past, so try later ones
+    let mut local = now.naive_local();
+    let target_date = loop {
+        // schedules are validated to fire when parsed
+        let next = schedule.next_after(local).unwrap();
+        let target_date = resolve_local(tz, next);
+        if target_date > now {
+            break target_date;
+        }
+        local = next;
+    };
+    let duration = target_date
+        .signed_duration_since(now)
+        .to_std()
+        .unwrap_or_default();
 
     let seconds = duration.as_secs() % 60;
     let minutes = (duration.as_secs() / 60) % 60;
@@ -98,3 +99,38 @@ pub fn get_dur_until_next_target_date(
     );
     duration
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::America::Denver;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn next_target_in_the_fall_back_overlap_is_ahead() {
+        // 01:30 MST, the second time 01:30 comes around on 2023-11-05
+        let now = utc("2023-11-05T08:30:00Z");
+
+        // 01:45 was first passed at 07:45 UTC, the next is 02:00 MST
+        let every: Schedule = "every 15m".parse().unwrap();
+        let d = get_dur_until_next_target_date(now, &every, Denver);
+        assert_eq!(d, Duration::from_secs(30 * 60));
+
+        // 01:45 today ran in MDT, so the next run is tomorrow's
+        let daily: Schedule = "daily at 01:45".parse().unwrap();
+        let d = get_dur_until_next_target_date(now, &daily, Denver);
+        assert_eq!(d, Duration::from_secs((24 * 60 + 15) * 60));
+    }
+
+    #[test]
+    fn next_target_before_the_overlap() {
+        // 01:30 MDT, the first time around
+        let now = utc("2023-11-05T07:30:00Z");
+        let every: Schedule = "every 15m".parse().unwrap();
+        let d = get_dur_until_next_target_date(now, &every, Denver);
+        assert_eq!(d, Duration::from_secs(15 * 60));
+    }
+}
@@ -0,0 +1,472 @@
+use log::{debug, error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cfg::StratTakeProfit;
+use crate::error::Error;
+use crate::exchange::{Exchange, SymbolDetail, Ticker};
+
+use crate::strats::dca_dips::{explicit_ladder, ladder, weighting_from_cfg, Rung};
+use crate::strats::funds::available_balance;
+use crate::strats::order_util;
+use crate::strats::state::{strat_state, Position, Store};
+use crate::strats::strat;
+use order_util::*;
+use strat::Execute;
+
+// the ladder described by a `[[strat_take_profit]]` table. an explicit
+// `ladder` list takes precedence over the interval, depth and weighting.
+fn ladder_from_cfg(cfg: &StratTakeProfit) -> Result<Vec<Rung>, String> {
+    let rungs = match &cfg.ladder {
+        Some(rungs) => explicit_ladder(rungs)?,
+        None => {
+            let weighting = weighting_from_cfg(cfg.weighting.as_deref(), cfg.geometric_ratio)?;
+            ladder(
+                cfg.profit_interval.unwrap_or_else(|| Decimal::from(5)),
+                cfg.profit_depth.unwrap_or_else(|| Decimal::from(25)),
+                &weighting,
+            )?
+        }
+    };
+    if rungs.is_empty() {
+        return Err("take profit ladder has no rungs".to_owned());
+    }
+    if rungs
+        .iter()
+        .any(|r| r.pct <= Decimal::ZERO || r.weight < Decimal::ZERO)
+    {
+        return Err("take profit ladder rungs need pct > 0 and weight >= 0".to_owned());
+    }
+    if rungs.iter().map(|r| r.weight).sum::<Decimal>().is_zero() {
+        return Err("take profit ladder weights sum to zero".to_owned());
+    }
+    Ok(rungs)
+}
+
+/// Sells `portion` of what the `sources` strats have bought with maker
+/// orders laddered above their average cost. Coins of an order that hasn't
+/// filled by the next window go back into its rung's bucket.
+pub struct TakeProfitStrat {
+    sources: Vec<Store>,
+    portion: Decimal,
+    rungs: Vec<Rung>,
+    // coins waiting to be offered at each rung
+    buckets: Vec<Decimal>,
+    // coins of the sources' holdings handed to the buckets so far
+    allotted: Decimal,
+    sold: Position,
+}
+
+impl TakeProfitStrat {
+    pub fn new(sources: Vec<Store>, portion: Decimal, rungs: Vec<Rung>) -> TakeProfitStrat {
+        let n_rungs = rungs.len();
+        TakeProfitStrat {
+            sources,
+            portion,
+            rungs,
+            buckets: vec![Decimal::ZERO; n_rungs],
+            allotted: Decimal::ZERO,
+            sold: Position::default(),
+        }
+    }
+
+    /// The strategy described by a `[[strat_take_profit]]` table, reading
+    /// the sources' positions from their state in `state_dir`. The sources
+    /// must be among the `strats` configured, given by id and symbol.
+    pub fn from_cfg(
+        cfg: &StratTakeProfit,
+        strats: &[(String, &str)],
+        state_dir: &str,
+    ) -> Result<TakeProfitStrat, String> {
+        if cfg.sources.is_empty() {
+            return Err("take profit needs at least one source".to_owned());
+        }
+        if cfg.portion_pct <= Decimal::ZERO || cfg.portion_pct > Decimal::ONE_HUNDRED {
+            return Err("portion_pct needs 0 < pct <= 100".to_owned());
+        }
+        // the holdings of another symbol aren't there to sell
+        for source in &cfg.sources {
+            match strats.iter().find(|(id, _)| id == source) {
+                None => return Err(format!("take profit source {} isn't a strat", source)),
+                Some((_, symbol)) if !symbol.eq_ignore_ascii_case(&cfg.common.symbol) => {
+                    return Err(format!(
+                        "take profit source {} trades {} not {}",
+                        source, symbol, cfg.common.symbol
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        let sources = cfg
+            .sources
+            .iter()
+            .map(|id| Store::new(state_dir, id))
+            .collect();
+        Ok(TakeProfitStrat::new(
+            sources,
+            cfg.portion_pct / Decimal::ONE_HUNDRED,
+            ladder_from_cfg(cfg)?,
+        ))
+    }
+
+    // everything the sources have bought so far, sold coins included
+    fn bought(&mut self) -> Position {
+        let mut held = Position::default();
+        for store in self.sources.iter_mut() {
+            if let Some(position) = store.load().and_then(|state| state.position()) {
+                held.amount += position.amount;
+                held.cost += position.cost;
+            }
+        }
+        held
+    }
+}
+
+// coins waiting at each rung, and of the sources' coins how many were
+// handed out and sold
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct TakeProfitState {
+    buckets: Vec<Decimal>,
+    allotted: Decimal,
+    sold: Position,
+}
+
+impl Execute for TakeProfitStrat {
+    fn execute(
+        &mut self,
+        client: &dyn Exchange,
+        id: &str,
+        symbol: &str,
+        orders: &mut Vec<u64>,
+        dt: &SymbolDetail,
+        tk: &Ticker,
+    ) -> Result<(), Error> {
+        // check on status of sell orders
+        let statuses = order_statuses(client, symbol, orders)?;
+        // orders whose cancel didn't go through, checked on again next run
+        let mut unsettled = vec![];
+        for (order_id, status) in statuses {
+            let client_order_id = status.client_order_id.clone().unwrap_or_default();
+            let (_, tag) = split_client_order_id(&client_order_id);
+            let bucket_num = match tag.parse::<usize>() {
+                // the ladder may have been reconfigured since the order was placed
+                Ok(n) if n > 0 => (n - 1).min(self.buckets.len() - 1),
+                _ => {
+                    warn!("{{{}}}: unknown order {} id={}", symbol, tag, order_id);
+                    continue;
+                }
+            };
+            let pct = self.rungs[bucket_num].pct;
+
+            // cancel live orders and add remainder to that bucket's next trade
+            if status.is_live {
+                let status = match client.cancel_order(order_id) {
+                    Ok(status) if status.is_cancelled => status,
+                    Ok(status) => {
+                        error!(
+                            "{{{}}}: error cancelling {} order id={} ({}), checking on it next run",
+                            symbol,
+                            tag,
+                            order_id,
+                            status.reason.unwrap_or_default()
+                        );
+                        unsettled.push(order_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(
+                            "{{{}}}: could not cancel {} order id={}: {}, checking on it next run",
+                            symbol, tag, order_id, e
+                        );
+                        unsettled.push(order_id);
+                        continue;
+                    }
+                };
+                self.sold.add_fill(&status);
+                self.buckets[bucket_num] += status.remaining_amount;
+                info!(
+                    "{{{}}}: {} order id={} has {} remaining on it. cancelling and adding to bucket {}% = {}",
+                    symbol, tag, status.order_id,
+                    status.remaining_amount, pct, self.buckets[bucket_num]
+                );
+
+            // add remainder of cancelled orders to that bucket's next trade
+            } else if status.is_cancelled {
+                self.sold.add_fill(&status);
+                if status.remaining_amount > Decimal::ZERO {
+                    self.buckets[bucket_num] += status.remaining_amount;
+                    info!(
+                        "{{{}}}: {} order id={} cancelled, had {} remaining on it, adding to bucket {}% = {}",
+                        symbol, tag, status.order_id,
+                        status.remaining_amount, pct, self.buckets[bucket_num]
+                    );
+                }
+            } else {
+                self.sold.add_fill(&status);
+                info!(
+                    "{{{}}}: {} order id={} sold! {} @ ${}",
+                    symbol,
+                    tag,
+                    status.order_id,
+                    status.executed_amount,
+                    status.avg_execution_price
+                );
+            }
+        }
+        *orders = unsettled;
+
+        let bought = self.bought();
+        let avg_cost = match bought.avg_cost() {
+            Some(avg_cost) => avg_cost,
+            None => {
+                info!("{{{}}}: sources hold nothing yet", symbol);
+                return Ok(());
+            }
+        };
+        // the sources' positions only ever grow, what was sold comes off here
+        let held = (bought.amount - self.sold.amount).max(Decimal::ZERO);
+
+        // hand the portion of newly bought coins to the buckets
+        let allotment = bought.amount * self.portion - self.allotted;
+        if allotment > Decimal::ZERO {
+            let total_weight: Decimal = self.rungs.iter().map(|r| r.weight).sum();
+            for (bucket, rung) in self.buckets.iter_mut().zip(&self.rungs) {
+                *bucket += allotment * rung.weight / total_weight;
+            }
+            self.allotted += allotment;
+        }
+        info!(
+            "{{{}}}: sources hold {} @ avg ${:.2}, sold {} for ${:.2} so far",
+            symbol, held, avg_cost, self.sold.amount, self.sold.cost
+        );
+        debug!("buckets: {:#?}", &self.buckets);
+
+        // place sell orders above the average cost, never below the ask so
+        // they rest on the book. a bucket too small to trade is merged into
+        // the next higher one.
+        // only what the account holds of the sources' coins can be offered,
+        // the lowest rungs first. the rest waits in its bucket
+        let mut available = available_balance(client, &dt.base_currency)?.min(held);
+        let mut carry = Decimal::ZERO;
+        let mut untracked = vec![];
+        for i in 0..self.buckets.len() {
+            let pct = self.rungs[i].pct;
+            let price = (avg_cost * (Decimal::ONE + pct / Decimal::ONE_HUNDRED)).max(tk.ask);
+            let amount = self.buckets[i] + carry;
+            self.buckets[i] = Decimal::ZERO;
+            carry = Decimal::ZERO;
+            if amount > available {
+                warn!(
+                    "{{{}}}: {}% holds {} but only {} {} is available, keeping the rest in the bucket",
+                    symbol, pct, amount, available, dt.base_currency
+                );
+                self.buckets[i] = amount - available;
+            }
+            let amount = amount.min(available);
+            if amount.is_zero() {
+                continue;
+            }
+            if below_min_order_size(dt, amount) {
+                info!(
+                    "{{{}}}: {}% {} is below the minimum order size {}, merging into next bucket",
+                    symbol, pct, amount, dt.min_order_size
+                );
+                carry = amount;
+                continue;
+            }
+            let client_order_id = client_order_id(id, &format!("{}", i + 1));
+            let status = new_maker_order(
+                client,
+                dt,
+                &client_order_id,
+                symbol,
+                Side::Sell,
+                amount,
+                price,
+            );
+            let status = match placed_maker(client, symbol, &client_order_id, orders, status) {
+                Placed::Order(status) => status,
+                Placed::Refused => {
+                    self.buckets[i] += amount;
+                    continue;
+                }
+                // dropped rather than risk selling twice
+                Placed::Unknown => continue,
+            };
+            if status.is_cancelled {
+                warn!(
+                    "{{{}}}: order was cancelled! ({})",
+                    symbol,
+                    status.reason.unwrap_or_default()
+                );
+                self.buckets[i] += amount;
+            } else {
+                info!(
+                    "{{{}}}: {}% order id={} {:.6} @ ${:.2}",
+                    symbol, pct, status.order_id, amount, price
+                );
+                available -= amount;
+                match status.order_id.parse() {
+                    Ok(order_id) => orders.push(order_id),
+                    Err(_) => untracked.push(status.order_id),
+                }
+            }
+        }
+        if carry > Decimal::ZERO {
+            info!(
+                "{{{}}}: holding {} below the minimum order size to next cycle",
+                symbol, carry
+            );
+            if let Some(last) = self.buckets.last_mut() {
+                *last += carry;
+            }
+        }
+        debug!("{{{}}}: order_ids: {:?}", symbol, orders);
+        if !untracked.is_empty() {
+            return Err(Error::Parse(format!(
+                "the ids {:?} of live orders, they aren't tracked",
+                untracked
+            )));
+        }
+        Ok(())
+    }
+
+    fn save_state(&self) -> Value {
+        json!(TakeProfitState {
+            buckets: self.buckets.clone(),
+            allotted: self.allotted,
+            sold: self.sold,
+        })
+    }
+
+    fn load_state(&mut self, state: &Value) {
+        let state: TakeProfitState = strat_state(state);
+        match state.buckets.len() {
+            0 => {}
+            n if n == self.buckets.len() => self.buckets = state.buckets,
+            n => {
+                warn!(
+                    "saved state has {} buckets but {} are configured, adding them to the first",
+                    n,
+                    self.buckets.len()
+                );
+                self.buckets[0] = state.buckets.iter().sum();
+            }
+        }
+        self.allotted = state.allotted;
+        self.sold = state.sold;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::sim::SimExchange;
+    use crate::strats::dca_dips::Weighting;
+    use crate::strats::state::{State, STATE_VERSION};
+    use chrono::{Duration, TimeZone, Utc};
+    use tempfile::TempDir;
+
+    fn sim(prices: &[i64]) -> SimExchange {
+        let prices: Vec<Decimal> = prices.iter().map(|p| Decimal::from(*p)).collect();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut sim = SimExchange::from_prices("btcusd", start, Duration::hours(1), &prices);
+        sim.fund("BTC", Decimal::from(20));
+        sim
+    }
+
+    // the source has bought `amount` coins at $100
+    fn bought(dir: &TempDir, amount: i64) {
+        let position = Position {
+            amount: Decimal::from(amount),
+            cost: Decimal::from(amount * 100),
+        };
+        let state = State {
+            version: STATE_VERSION,
+            strat: json!({ "position": position }),
+            ..Default::default()
+        };
+        let store = Store::new(dir.path().to_str().unwrap(), "dips_btcusd");
+        assert!(store.save(&state));
+    }
+
+    // sells all the source bought, half at 10% and half at 20% up
+    fn strat(dir: &TempDir) -> TakeProfitStrat {
+        let sources = vec![Store::new(dir.path().to_str().unwrap(), "dips_btcusd")];
+        let rungs = ladder(Decimal::from(10), Decimal::from(20), &Weighting::Flat).unwrap();
+        TakeProfitStrat::new(sources, Decimal::ONE, rungs)
+    }
+
+    fn execute(strat: &mut TakeProfitStrat, sim: &SimExchange, orders: &mut Vec<u64>) {
+        let dt = sim.symbol_detail("btcusd").unwrap();
+        let tk = sim.ticker("btcusd").unwrap();
+        strat
+            .execute(sim, "take_profit_btcusd", "btcusd", orders, &dt, &tk)
+            .unwrap();
+    }
+
+    // the amount and price of each order
+    fn offered(sim: &SimExchange, orders: &[u64]) -> Vec<(Decimal, Decimal)> {
+        orders
+            .iter()
+            .map(|id| {
+                let status = sim.order_status(*id).unwrap();
+                (status.original_amount, status.price)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sells_up_the_ladder_and_counts_what_sold() {
+        let dir = TempDir::new().unwrap();
+        bought(&dir, 4);
+        let mut sim = sim(&[100, 115]);
+        let mut strat = strat(&dir);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders);
+        assert_eq!(
+            offered(&sim, &orders),
+            vec![
+                (Decimal::from(2), Decimal::from(110)),
+                (Decimal::from(2), Decimal::from(120))
+            ]
+        );
+
+        // the 10% rung sells, the 20% one is laid again and the sold coins
+        // aren't handed out again
+        sim.advance();
+        execute(&mut strat, &sim, &mut orders);
+        assert_eq!(strat.sold.amount, Decimal::from(2));
+        assert_eq!(strat.sold.cost, Decimal::from(220));
+        assert_eq!(
+            offered(&sim, &orders),
+            vec![(Decimal::from(2), Decimal::from(120))]
+        );
+        assert_eq!(strat.buckets, vec![Decimal::ZERO; 2]);
+    }
+
+    #[test]
+    fn offers_no_more_than_the_sources_still_hold() {
+        let dir = TempDir::new().unwrap();
+        bought(&dir, 4);
+        let mut sim = sim(&[100, 115, 100]);
+        let mut strat = strat(&dir);
+        let mut orders = vec![];
+        execute(&mut strat, &sim, &mut orders);
+        sim.advance();
+        execute(&mut strat, &sim, &mut orders);
+
+        // the source's state now says it bought 3, of which 2 were sold.
+        // the account holds more, but only 1 of the source's is left
+        bought(&dir, 3);
+        sim.advance();
+        execute(&mut strat, &sim, &mut orders);
+        assert_eq!(
+            offered(&sim, &orders),
+            vec![(Decimal::ONE, Decimal::from(120))]
+        );
+        assert_eq!(strat.buckets[1], Decimal::ONE);
+    }
+}